@@ -0,0 +1,248 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use id3::{Tag, TagLike};
+
+use crate::{
+    backend::{Backend, FfmpegBackend},
+    chapters::{self, ChapterOffsets, ChapterStart, ChapterTitle, UNUSED_OFFSET},
+    concat,
+    inputs::{self, InputOrder},
+    interrupt, metadata, mp3, output, probe, timing,
+    transcode::{self, Encoding, Profile},
+    volumes,
+};
+
+/// Builder for adding new inputs to the end of an already merged MP3 file, in place.
+///
+/// The existing audio is stream copied, and new inputs are converted to match it if needed. Each
+/// new input gets a chapter continuing from the end of the last existing chapter, and every other
+/// frame in the existing tag is kept as is.
+#[derive(Clone, Debug)]
+pub struct Appender {
+    path: PathBuf,
+    inputs: Vec<PathBuf>,
+    input_order: InputOrder,
+    chapter_title: ChapterTitle,
+    chapter_offsets: ChapterOffsets,
+    encoding: Encoding,
+    jobs: usize,
+    backend: Arc<dyn Backend>,
+}
+
+impl Appender {
+    /// Create a new append to the given merged file.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            inputs: Vec::new(),
+            input_order: InputOrder::default(),
+            chapter_title: ChapterTitle::default(),
+            chapter_offsets: ChapterOffsets::default(),
+            encoding: Encoding::default(),
+            jobs: probe::default_jobs(),
+            backend: Arc::new(FfmpegBackend),
+        }
+    }
+
+    /// Add a single input file, directory, or glob pattern to append.
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    /// Add several input files, directories or glob patterns to append, in order.
+    pub fn inputs(mut self, paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.inputs.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// How to order the files found in each directory or glob pattern. Defaults to natural order.
+    pub fn input_order(mut self, order: InputOrder) -> Self {
+        self.input_order = order;
+        self
+    }
+
+    /// Where to get the title of each new chapter from. Defaults to the file stem.
+    ///
+    /// The `{n}` template field keeps counting from the existing chapters.
+    pub fn chapter_title(mut self, source: ChapterTitle) -> Self {
+        self.chapter_title = source;
+        self
+    }
+
+    /// How to fill in chapter byte offsets, for both existing and new chapters. Defaults to the
+    /// positions of the frames each chapter starts and ends at.
+    pub fn chapter_offsets(mut self, offsets: ChapterOffsets) -> Self {
+        self.chapter_offsets = offsets;
+        self
+    }
+
+    /// Encoder settings for new inputs that have to be re-encoded. Defaults to VBR quality 2.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// How many input files to probe at once. Defaults to the number of CPUs.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// The backend used to probe, concatenate and transcode files. Defaults to
+    /// [`FfmpegBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Append the inputs, extend the chapters, and replace the original file with the result.
+    pub fn append(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.inputs.is_empty(), "no input files specified");
+        let display = self.path.display();
+
+        let existing = probe::probe(&*self.backend, &self.path)
+            .with_context(|| format!("failed to probe merged file '{display}'"))?;
+        anyhow::ensure!(
+            existing.codec_name == "mp3",
+            "can only append to MP3 files, but '{display}' is {}",
+            existing.codec_name
+        );
+
+        // A merged file without any tag yet just gets a new one.
+        let mut tag = id3::no_tag_ok(Tag::read_from_path(&self.path))
+            .with_context(|| format!("failed to read ID3 tag from '{display}'"))?
+            .unwrap_or_else(Tag::new);
+
+        let expanded = inputs::expand_inputs(&self.inputs).context("failed to find input files")?;
+        let (inputs, probes) =
+            inputs::collect_inputs(&*self.backend, expanded, self.input_order, self.jobs)?;
+
+        // New chapters pick up where the last one ends, or where the audio ends without any.
+        let last = tag.chapters().max_by_key(|chapter| chapter.end_time);
+        let start = ChapterStart {
+            index: tag.chapters().count(),
+            time: last.map_or_else(
+                || (existing.duration_secs * 1000.0).round() as u32,
+                |chapter| chapter.end_time,
+            ),
+        };
+
+        let total_ms = u64::from(start.time) + probes.iter().map(volumes::duration_ms).sum::<u64>();
+        anyhow::ensure!(
+            total_ms <= volumes::MAX_DURATION_MS,
+            "appending would make '{display}' longer than chapter times can hold"
+        );
+
+        let mut next_id = start.index;
+        let mut new_chapters = chapters::get_chapters(&inputs, &probes, &self.chapter_title, start)
+            .context("failed to generate chapter metadata")?;
+
+        // Chapter IDs count on from the existing chapters like their numbers do, but skip any
+        // that are still in the file, which happens once chapters have been deleted.
+        for chapter in &mut new_chapters {
+            while tag
+                .chapters()
+                .any(|old| old.element_id == format!("chapter_{next_id}"))
+            {
+                next_id += 1;
+            }
+
+            chapter.element_id = format!("chapter_{next_id}");
+            next_id += 1;
+        }
+
+        // New chapters go at the end of the top-level table of contents, after any nested ones.
+        let top_level = tag.tables_of_contents().find(|toc| toc.top_level).cloned();
+
+        if let Some(mut toc) = top_level {
+            toc.elements.extend(
+                new_chapters
+                    .iter()
+                    .map(|chapter| chapter.element_id.clone()),
+            );
+            tag.add_frame(toc);
+        }
+
+        // Leave space for the tag with the new chapters in front of the audio, to fill in once
+        // their times are known.
+        let mut sized_tag = tag.clone();
+        for chapter in &new_chapters {
+            sized_tag.add_frame(chapter.clone());
+        }
+        let tag_space = metadata::tag_space(&sized_tag)?;
+
+        // Keep the transcoded files around until the merge is done.
+        let profile = Profile::of(&existing);
+        let (files, _transcode_dir) =
+            transcode::transcode_inputs(&*self.backend, &inputs, &probes, &profile, self.encoding)
+                .context("failed to transcode input files")?;
+
+        let mut mergelist = vec![self.path.clone()];
+        mergelist.extend(files);
+        let merged_file = concat::merge_files(&*self.backend, &mergelist, &self.path, tag_space)
+            .context("failed to merge input files")?;
+
+        // The existing file gets an empty group, so only the new chapters move.
+        let mut chapters = vec![Vec::new()];
+        chapters.extend(new_chapters.into_iter().map(|chapter| vec![chapter]));
+
+        let mut durations = vec![start.time];
+        durations.extend(
+            probes
+                .iter()
+                .map(|probe| (probe.duration_secs * 1000.0).round() as u32),
+        );
+
+        let stream = timing::correct_chapters(
+            &*self.backend,
+            &mut chapters,
+            &durations,
+            &mergelist,
+            merged_file.path(),
+        )
+        .context("failed to correct chapter timings")?;
+
+        let new_chapters: Vec<_> = chapters.into_iter().flatten().collect();
+
+        if tag.get("TLEN").is_some() {
+            let length = new_chapters
+                .last()
+                .map_or(start.time, |chapter| chapter.end_time);
+            tag.set_text("TLEN", length.to_string());
+        }
+
+        // The existing audio moved too, since the old tag and Xing header are gone, so every
+        // chapter gets new offsets.
+        let mut all_chapters: Vec<_> = tag.chapters().cloned().chain(new_chapters).collect();
+
+        match self.chapter_offsets {
+            ChapterOffsets::Frames => stream.set_offsets(&mut all_chapters),
+            ChapterOffsets::Unused => {
+                for chapter in &mut all_chapters {
+                    chapter.start_offset = UNUSED_OFFSET;
+                    chapter.end_offset = UNUSED_OFFSET;
+                }
+            }
+        }
+
+        for chapter in all_chapters {
+            tag.add_frame(chapter);
+        }
+
+        let space = mp3::audio_start(merged_file.path())
+            .context("failed to find start of audio in merged file")?;
+        chapters::rebase_offsets(&mut tag, 0, space)?;
+
+        metadata::write_tag(&tag, merged_file.path(), space)
+            .context("failed to write ID3 metadata to merged file")?;
+
+        interrupt::check()?;
+
+        output::persist(merged_file, &self.path)
+            .with_context(|| format!("failed to replace '{display}' with the merged file"))?;
+
+        Ok(())
+    }
+}
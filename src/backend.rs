@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    concat, mp3, mp4,
+    probe::{self, Packet, Probe},
+    split,
+    transcode::{self, Encoding, Profile},
+};
+
+/// The tools that do the actual work on media files: probing, joining and converting them.
+///
+/// MP3 files are scanned and joined natively wherever possible, so a backend is only asked about
+/// other formats, or about streams that can't simply be joined frame by frame.
+pub trait Backend: Debug + Send + Sync {
+    /// Find the duration, audio stream parameters and tags of a file.
+    fn probe(&self, path: &Path) -> anyhow::Result<Probe>;
+
+    /// Count the packets in the audio stream of a file, without decoding it.
+    fn count_packets(&self, path: &Path) -> anyhow::Result<usize>;
+
+    /// List every packet in the audio stream of a file, in order.
+    fn packets(&self, path: &Path) -> anyhow::Result<Vec<Packet>>;
+
+    /// Join files with the same stream parameters into `output`, without re-encoding them.
+    fn concatenate(&self, inputs: &[&Path], output: &Path) -> anyhow::Result<()>;
+
+    /// Convert a file to the given stream parameters, without any tags.
+    ///
+    /// `progress` is called with how many seconds of output have been written so far, as often as
+    /// the backend knows.
+    fn transcode(
+        &self,
+        input: &Path,
+        output: &Path,
+        profile: &Profile,
+        encoding: Encoding,
+        progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()>;
+
+    /// Encode files with the same stream parameters into a single M4B file with AAC audio, taking
+    /// tags and chapters from an FFMETADATA file, and cover art from an image if there is one.
+    ///
+    /// `progress` is called like it is for [`Backend::transcode`].
+    fn encode_m4b(
+        &self,
+        inputs: &[&Path],
+        ffmetadata: &Path,
+        cover: Option<&Path>,
+        encoding: Encoding,
+        output: &Path,
+        progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()>;
+
+    /// Cut the audio from `start_ms` to `end_ms` out of an MP3 file into `output`, without any
+    /// tags. The audio is stream copied, unless an `encoding` to re-encode it with is given.
+    fn cut(
+        &self,
+        input: &Path,
+        output: &Path,
+        start_ms: u32,
+        end_ms: u32,
+        encoding: Option<Encoding>,
+    ) -> anyhow::Result<()>;
+}
+
+/// The default backend, which runs `ffmpeg` and `ffprobe` from the PATH.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FfmpegBackend;
+
+impl Backend for FfmpegBackend {
+    fn probe(&self, path: &Path) -> anyhow::Result<Probe> {
+        probe::ffprobe(path)
+    }
+
+    fn count_packets(&self, path: &Path) -> anyhow::Result<usize> {
+        probe::ffprobe_count_packets(path)
+    }
+
+    fn packets(&self, path: &Path) -> anyhow::Result<Vec<Packet>> {
+        probe::ffprobe_packets(path)
+    }
+
+    fn concatenate(&self, inputs: &[&Path], output: &Path) -> anyhow::Result<()> {
+        concat::ffmpeg_concat(inputs, output)
+    }
+
+    fn transcode(
+        &self,
+        input: &Path,
+        output: &Path,
+        profile: &Profile,
+        encoding: Encoding,
+        progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()> {
+        transcode::ffmpeg_transcode(input, output, profile, encoding, progress)
+    }
+
+    fn encode_m4b(
+        &self,
+        inputs: &[&Path],
+        ffmetadata: &Path,
+        cover: Option<&Path>,
+        encoding: Encoding,
+        output: &Path,
+        progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()> {
+        mp4::ffmpeg_encode(inputs, ffmetadata, cover, encoding, output, progress)
+    }
+
+    fn cut(
+        &self,
+        input: &Path,
+        output: &Path,
+        start_ms: u32,
+        end_ms: u32,
+        encoding: Option<Encoding>,
+    ) -> anyhow::Result<()> {
+        split::ffmpeg_cut(input, output, start_ms, end_ms, encoding)
+    }
+}
+
+/// A deterministic backend that never runs anything, for testing merges without ffmpeg.
+///
+/// Probes are looked up from the ones given to [`FakeBackend::probe_as`]. Transcoding writes silent
+/// MP3 frames for the probed duration, whatever the codec asked for, so merged output can be
+/// measured like a real one. Concatenating simply writes the inputs one after another, and encoding
+/// M4B output writes the FFMETADATA file instead, so the tags and chapters it would get can be
+/// checked. Cutting copies the MP3 frames that start within the cut, even when re-encoding.
+#[derive(Clone, Debug, Default)]
+pub struct FakeBackend {
+    probes: HashMap<PathBuf, Probe>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report the given probe for a file, which must still exist for inputs to be found.
+    pub fn probe_as(mut self, path: impl Into<PathBuf>, probe: Probe) -> Self {
+        self.probes.insert(path.into(), probe);
+        self
+    }
+
+    fn get(&self, path: &Path) -> anyhow::Result<&Probe> {
+        self.probes
+            .get(path)
+            .with_context(|| format!("no fake probe for '{}'", path.display()))
+    }
+}
+
+impl Backend for FakeBackend {
+    fn probe(&self, path: &Path) -> anyhow::Result<Probe> {
+        self.get(path).cloned()
+    }
+
+    fn count_packets(&self, path: &Path) -> anyhow::Result<usize> {
+        Ok(self.packets(path)?.len())
+    }
+
+    fn packets(&self, path: &Path) -> anyhow::Result<Vec<Packet>> {
+        anyhow::bail!(
+            "fake backend can't list packets of '{}', which isn't MP3",
+            path.display()
+        )
+    }
+
+    fn concatenate(&self, inputs: &[&Path], output: &Path) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(output)?);
+
+        for path in inputs {
+            writer.write_all(&fs::read(path)?)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn transcode(
+        &self,
+        input: &Path,
+        output: &Path,
+        profile: &Profile,
+        _encoding: Encoding,
+        progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()> {
+        let duration_secs = self.get(input)?.duration_secs;
+        let mut writer = BufWriter::new(File::create(output)?);
+
+        mp3::write_silence(
+            &mut writer,
+            profile.sample_rate,
+            profile.channels,
+            duration_secs,
+        )?;
+        writer.flush()?;
+        progress(duration_secs);
+
+        Ok(())
+    }
+
+    fn encode_m4b(
+        &self,
+        _inputs: &[&Path],
+        ffmetadata: &Path,
+        _cover: Option<&Path>,
+        _encoding: Encoding,
+        output: &Path,
+        _progress: &mut dyn FnMut(f64),
+    ) -> anyhow::Result<()> {
+        fs::copy(ffmetadata, output)?;
+        Ok(())
+    }
+
+    fn cut(
+        &self,
+        input: &Path,
+        output: &Path,
+        start_ms: u32,
+        end_ms: u32,
+        _encoding: Option<Encoding>,
+    ) -> anyhow::Result<()> {
+        let stream = mp3::scan(input)?.with_context(|| {
+            format!(
+                "fake backend can't cut '{}', which isn't MP3",
+                input.display()
+            )
+        })?;
+        let bytes = fs::read(input)?;
+        let frame_ms = stream.frame_secs() * 1000.0;
+        let mut writer = BufWriter::new(File::create(output)?);
+
+        for (i, frame) in stream.frames.iter().enumerate() {
+            let time = (i as f64 * frame_ms).round() as u32;
+
+            if (start_ms..end_ms).contains(&time) {
+                let start = frame.pos as usize;
+                let end = (start + frame.len as usize).min(bytes.len());
+                writer.write_all(&bytes[start..end])?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
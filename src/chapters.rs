@@ -0,0 +1,381 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::Context;
+use id3::{frame::Chapter, Tag, TagLike};
+
+use crate::{metadata, probe::Probe};
+
+/// Chapter offsets set to this value mean "unused", according to the ID3 chapter spec.
+pub(crate) const UNUSED_OFFSET: u32 = u32::MAX;
+
+/// How to fill in the byte offsets of chapters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChapterOffsets {
+    /// The positions of the MPEG frames each chapter starts and ends at, measured in the output.
+    #[default]
+    Frames,
+    /// The "unused" value from the ID3 chapter spec, leaving players to seek by time.
+    Unused,
+}
+
+impl FromStr for ChapterOffsets {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "frames" => Ok(Self::Frames),
+            "unused" => Ok(Self::Unused),
+            _ => Err(format!(
+                "unknown chapter offsets '{name}', expected frames or unused"
+            )),
+        }
+    }
+}
+
+/// Where to get the title of each per-file chapter from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChapterTitle {
+    /// The input file name, without its extension.
+    #[default]
+    Stem,
+    /// The title (`TIT2`) from the input file's ID3 tag.
+    Tag,
+    /// The `title` tag reported by ffprobe, which works for any container.
+    Probe,
+    /// A template such as `{n:02} - {tag.title}`.
+    ///
+    /// Supported fields are `{n}` (the chapter number, starting from 1), `{stem}`,
+    /// `{tag.title}`, `{tag.artist}`, `{tag.album}`, `{tag.track}`, and `{probe.<name>}` for any
+    /// tag reported by ffprobe. A field can be padded to a width with `{field:3}`, or zero-padded
+    /// with `{field:03}`.
+    Template(String),
+}
+
+impl FromStr for ChapterTitle {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        match source {
+            "stem" => Ok(Self::Stem),
+            "tag" => Ok(Self::Tag),
+            "probe" => Ok(Self::Probe),
+            template if template.contains('{') => {
+                parse_template(template)?;
+                Ok(Self::Template(template.to_string()))
+            }
+            _ => Err(format!(
+                "unknown chapter title source '{source}', expected stem, tag, probe, or a template"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Literal(&'a str),
+    Field {
+        name: &'a str,
+        width: usize,
+        zero_pad: bool,
+    },
+}
+
+fn parse_template(template: &str) -> Result<Vec<Segment<'_>>, String> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Literal(&rest[..start]));
+        }
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed '{{' in chapter title template '{template}'"))?;
+        let field = &rest[start + 1..start + end];
+        let (name, spec) = field.split_once(':').unwrap_or((field, ""));
+
+        let known = matches!(
+            name,
+            "n" | "stem" | "tag.title" | "tag.artist" | "tag.album" | "tag.track"
+        ) || name
+            .strip_prefix("probe.")
+            .is_some_and(|key| !key.is_empty());
+
+        if !known {
+            return Err(format!(
+                "unknown field '{{{name}}}' in chapter title template"
+            ));
+        }
+
+        segments.push(Segment::Field {
+            name,
+            width: if spec.is_empty() {
+                0
+            } else {
+                spec.parse()
+                    .map_err(|_| format!("invalid width '{spec}' in chapter title template"))?
+            },
+            zero_pad: spec.starts_with('0'),
+        });
+
+        rest = &rest[start + end + 1..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest));
+    }
+
+    Ok(segments)
+}
+
+/// Everything a chapter title can be built from, for a single input file.
+struct TitleFields<'a> {
+    number: usize,
+    stem: String,
+    probe: &'a Probe,
+}
+
+impl TitleFields<'_> {
+    /// A field of the input's ID3 tag, which probing MP3 files already read into their tags.
+    fn id3(&self, name: &str) -> Option<&str> {
+        (self.probe.codec_name == "mp3")
+            .then(|| self.probe.tags.get(name))
+            .flatten()
+            .map(String::as_str)
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "n" => Some(self.number.to_string()),
+            "stem" => Some(self.stem.clone()),
+            "tag.title" => self.id3("title").map(String::from),
+            "tag.artist" => self.id3("artist").map(String::from),
+            "tag.album" => self.id3("album").map(String::from),
+            // Like `TagLike::track`, leave out the total in e.g. `3/12`.
+            "tag.track" => (self.id3("track")?.split('/').next()?.trim())
+                .parse::<u32>()
+                .ok()
+                .map(|track| track.to_string()),
+            _ => self
+                .probe
+                .tags
+                .get(&name.strip_prefix("probe.")?.to_lowercase())
+                .cloned(),
+        }
+    }
+
+    fn render(&self, template: &str) -> anyhow::Result<Option<String>> {
+        let mut title = String::new();
+
+        for segment in parse_template(template).map_err(anyhow::Error::msg)? {
+            match segment {
+                Segment::Literal(text) => title.push_str(text),
+                Segment::Field {
+                    name,
+                    width,
+                    zero_pad,
+                } => match self.get(name) {
+                    Some(value) if zero_pad => title.push_str(&format!("{value:0>width$}")),
+                    Some(value) => title.push_str(&format!("{value:>width$}")),
+                    None => return Ok(None),
+                },
+            }
+        }
+
+        Ok(Some(title))
+    }
+
+    fn title(&self, source: &ChapterTitle) -> anyhow::Result<String> {
+        let title = match source {
+            ChapterTitle::Stem => None,
+            ChapterTitle::Tag => self.get("tag.title"),
+            ChapterTitle::Probe => self.get("probe.title"),
+            ChapterTitle::Template(template) => self.render(template)?,
+        };
+
+        Ok(title
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or_else(|| self.stem.clone()))
+    }
+}
+
+/// Where the first generated chapter starts, for continuing after existing chapters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ChapterStart {
+    pub index: usize,
+    pub time: u32,
+}
+
+pub(crate) fn get_chapters(
+    inputs: &[impl AsRef<Path>],
+    probes: &[Probe],
+    title_source: &ChapterTitle,
+    start: ChapterStart,
+) -> anyhow::Result<Vec<Chapter>> {
+    let mut chapters = Vec::with_capacity(inputs.len());
+    let mut current_time: u32 = start.time;
+
+    for (i, (path, probe)) in inputs.iter().map(AsRef::as_ref).zip(probes).enumerate() {
+        let i = start.index + i;
+        let display = path.display();
+
+        let duration_ms = (probe.duration_secs * 1000.0).round() as u32;
+
+        let mut chapter = Chapter {
+            element_id: format!("chapter_{i}"),
+            start_time: current_time,
+            end_time: current_time + duration_ms,
+            // Offsets are only known once the inputs are merged.
+            start_offset: UNUSED_OFFSET,
+            end_offset: UNUSED_OFFSET,
+            frames: vec![],
+        };
+
+        let fields = TitleFields {
+            number: i + 1,
+            stem: path
+                .file_stem()
+                .with_context(|| format!("failed to get stem for input file '{display}'"))?
+                .to_string_lossy()
+                .into_owned(),
+            probe,
+        };
+
+        chapter.set_title(fields.title(title_source)?);
+
+        current_time += duration_ms;
+
+        chapters.push(chapter);
+    }
+
+    Ok(chapters)
+}
+
+/// Rebase chapter byte offsets that count from `audio_start` to count from the start of the
+/// file, as the ID3 chapter spec wants, once `tag` is written in front of the audio by
+/// [`metadata::write_tag`] into `space` bytes.
+pub(crate) fn rebase_offsets(tag: &mut Tag, audio_start: u64, space: u64) -> anyhow::Result<()> {
+    // The offsets don't change the size of the tag, so it can be measured before they're set.
+    let tag_size = metadata::written_size(tag, space)?;
+
+    let rebase = |offset: u32| {
+        if offset == UNUSED_OFFSET {
+            return UNUSED_OFFSET;
+        }
+
+        (u64::from(offset).checked_sub(audio_start))
+            .and_then(|offset| u32::try_from(offset + tag_size).ok())
+            .unwrap_or(UNUSED_OFFSET)
+    };
+
+    let chapters: Vec<_> = tag.chapters().cloned().collect();
+
+    for mut chapter in chapters {
+        chapter.start_offset = rebase(chapter.start_offset);
+        chapter.end_offset = rebase(chapter.end_offset);
+        tag.add_frame(chapter);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tagged_probe;
+
+    fn fields<'a>(probe: &'a Probe) -> TitleFields<'a> {
+        TitleFields {
+            number: 7,
+            stem: String::from("07 - Track 07"),
+            probe,
+        }
+    }
+
+    #[test]
+    fn parses_templates() {
+        assert_eq!(
+            parse_template("{n:02} - {tag.title} ({probe.Narrator:5})"),
+            Ok(vec![
+                Segment::Field {
+                    name: "n",
+                    width: 2,
+                    zero_pad: true,
+                },
+                Segment::Literal(" - "),
+                Segment::Field {
+                    name: "tag.title",
+                    width: 0,
+                    zero_pad: false,
+                },
+                Segment::Literal(" ("),
+                Segment::Field {
+                    name: "probe.Narrator",
+                    width: 5,
+                    zero_pad: false,
+                },
+                Segment::Literal(")"),
+            ])
+        );
+
+        for (template, error) in [
+            ("{n", "unclosed '{' in chapter title template '{n'"),
+            (
+                "{tag.year}",
+                "unknown field '{tag.year}' in chapter title template",
+            ),
+            (
+                "{probe.}",
+                "unknown field '{probe.}' in chapter title template",
+            ),
+            ("{n:x}", "invalid width 'x' in chapter title template"),
+        ] {
+            assert_eq!(parse_template(template), Err(String::from(error)));
+            assert_eq!(template.parse::<ChapterTitle>(), Err(String::from(error)));
+        }
+    }
+
+    #[test]
+    fn renders_padded_fields() {
+        let probe = tagged_probe("mp3", &[("title", "Opening"), ("track", "3/12")]);
+        let fields = fields(&probe);
+
+        let render = |template| fields.render(template).unwrap();
+        assert_eq!(render("{n:3}|{n:03}|{n}"), Some(String::from("  7|007|7")));
+        assert_eq!(
+            render("{tag.track:02} - {tag.title}"),
+            Some(String::from("03 - Opening"))
+        );
+        assert_eq!(render("{stem}"), Some(String::from("07 - Track 07")));
+
+        // A field the input doesn't have leaves nothing to render.
+        assert_eq!(render("{n} - {tag.artist}"), None);
+        assert_eq!(render("{probe.narrator}"), None);
+
+        assert!(fields.render("{n} - {tag.year}").is_err());
+    }
+
+    #[test]
+    fn titles_fall_back_to_the_stem() {
+        let tagged = tagged_probe("mp3", &[("title", "Opening"), ("artist", "Author")]);
+        let untitled = tagged_probe("mp3", &[("title", "  ")]);
+        let flac = tagged_probe("flac", &[("title", "Opening")]);
+
+        let title = |probe, source: &str| fields(probe).title(&source.parse().unwrap()).unwrap();
+
+        assert_eq!(title(&tagged, "stem"), "07 - Track 07");
+        assert_eq!(title(&tagged, "tag"), "Opening");
+        assert_eq!(
+            title(&tagged, "{tag.artist}: {tag.title}"),
+            "Author: Opening"
+        );
+        assert_eq!(title(&untitled, "tag"), "07 - Track 07");
+        assert_eq!(title(&untitled, "{n}. {tag.album}"), "07 - Track 07");
+
+        // Only MP3 inputs have ID3 tags, but anything can have probed tags.
+        assert_eq!(title(&flac, "tag"), "07 - Track 07");
+        assert_eq!(title(&flac, "probe"), "Opening");
+        assert_eq!(title(&flac, "{probe.TITLE}"), "Opening");
+    }
+}
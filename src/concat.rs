@@ -0,0 +1,166 @@
+use std::{fs, io, path::Path, time::Duration};
+
+use anyhow::Context;
+use indicatif::ProgressBar;
+use tempfile::NamedTempFile;
+
+use crate::{
+    backend::Backend,
+    interrupt, metadata,
+    mp3::{self, Mp3Stream},
+    output,
+};
+
+/// Write a list of files for ffmpeg's concat demuxer to a temporary file, which is removed once
+/// it's dropped.
+///
+/// ffmpeg resolves relative paths against the list's own directory, so every path is made
+/// absolute first.
+pub(crate) fn create_mergelist(inputs: &[impl AsRef<Path>]) -> anyhow::Result<NamedTempFile> {
+    let mut lines = Vec::with_capacity(inputs.len());
+
+    for path in inputs.iter().map(AsRef::as_ref) {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("failed to resolve path of '{}'", path.display()))?;
+
+        // Within single quotes, everything but a single quote is taken literally.
+        let path = (path.to_str())
+            .with_context(|| {
+                format!(
+                    "path '{}' isn't valid UTF-8, which the mergelist needs",
+                    path.display()
+                )
+            })?
+            .replace('\'', "'\\''");
+        lines.push(format!("file '{path}'"));
+    }
+
+    let mergelist = tempfile::Builder::new()
+        .prefix("merge-mergelist")
+        .suffix(".txt")
+        .tempfile()?;
+    fs::write(mergelist.path(), lines.join("\n")).context("failed to write mergelist")?;
+
+    Ok(mergelist)
+}
+
+/// Scan every input, if they're all MP3 streams that can simply be joined frame by frame.
+fn scan_matching(inputs: &[impl AsRef<Path>]) -> io::Result<Option<Vec<Mp3Stream>>> {
+    let mut streams: Vec<Mp3Stream> = Vec::with_capacity(inputs.len());
+
+    for path in inputs {
+        let Some(stream) = mp3::scan(path.as_ref())? else {
+            return Ok(None);
+        };
+
+        if let Some(first) = streams.first() {
+            if !first.header.matches(&stream.header)
+                || first.header.channels != stream.header.channels
+            {
+                return Ok(None);
+            }
+        }
+
+        streams.push(stream);
+    }
+
+    Ok(Some(streams))
+}
+
+fn temp_mp3() -> io::Result<NamedTempFile> {
+    tempfile::Builder::new()
+        .prefix("merge-output")
+        .suffix(".mp3")
+        .tempfile()
+}
+
+/// Write the frames of the given streams to a new temporary file next to `output`, after
+/// `tag_space` bytes left for the tag and a new Xing header.
+fn write_frames(
+    inputs: &[impl AsRef<Path>],
+    streams: &[Mp3Stream],
+    output: &Path,
+    tag_space: u64,
+) -> anyhow::Result<NamedTempFile> {
+    let mut merged_file = output::temp_file_for(output, ".mp3")?;
+    let mut writer = io::BufWriter::new(merged_file.as_file_mut());
+
+    metadata::reserve_tag(&mut writer, tag_space)?;
+    mp3::concatenate(inputs, streams, &mut writer)?;
+    io::Write::flush(&mut writer)?;
+    drop(writer);
+
+    Ok(merged_file)
+}
+
+/// Join files with ffmpeg's concat demuxer, stream copying them into `output`.
+pub(crate) fn ffmpeg_concat(inputs: &[&Path], output: &Path) -> anyhow::Result<()> {
+    let mergelist = create_mergelist(inputs)?;
+
+    interrupt::run(duct::cmd!(
+        "ffmpeg",
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-f",
+        "concat",
+        "-safe",
+        "0",
+        "-i",
+        mergelist.path(),
+        "-c",
+        "copy",
+        "-y",
+        output
+    ))?;
+
+    Ok(())
+}
+
+/// Merge MP3 files into a temporary file next to `output`, without re-encoding.
+///
+/// Files with matching stream parameters are concatenated directly, and anything else is left to
+/// the backend. Either way, the result starts with an empty tag taking up `tag_space` bytes, for
+/// [`metadata::write_tag`] to fill in without moving the audio, and a Xing header for the whole
+/// stream.
+pub(crate) fn merge_files(
+    backend: &dyn Backend,
+    inputs: &[impl AsRef<Path>],
+    output: &Path,
+    tag_space: u64,
+) -> anyhow::Result<NamedTempFile> {
+    let progress_bar = ProgressBar::new_spinner().with_message("🔨 merging input files...");
+    progress_bar.enable_steady_tick(Duration::from_millis(100));
+
+    let merged_file = match scan_matching(inputs)? {
+        Some(streams) => write_frames(inputs, &streams, output, tag_space)?,
+        None => {
+            let joined_file = temp_mp3()?;
+            let paths: Vec<_> = inputs.iter().map(AsRef::as_ref).collect();
+            backend.concatenate(&paths, joined_file.path())?;
+
+            // Stream copying keeps the first input's Xing header, which only describes that
+            // input, so players would show the wrong length and seek to the wrong places.
+            match mp3::scan(joined_file.path())? {
+                Some(mut stream) => {
+                    progress_bar.set_message("🧭 rebuilding Xing header...");
+
+                    let last = match inputs.last() {
+                        Some(path) => mp3::scan(path.as_ref())?.and_then(|last| last.info),
+                        None => None,
+                    };
+                    if let (Some(info), Some(last)) = (&mut stream.info, last) {
+                        info.padding = last.padding;
+                    }
+
+                    write_frames(&[joined_file.path()], &[stream], output, tag_space)?
+                }
+                None => joined_file,
+            }
+        }
+    };
+
+    progress_bar.finish_with_message("💽 merged!");
+
+    Ok(merged_file)
+}
@@ -0,0 +1,406 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use id3::{frame::Chapter, TagLike};
+
+use crate::{chapters::UNUSED_OFFSET, probe::Probe};
+
+// CUE timestamps are in minutes, seconds, and CD frames.
+const FRAMES_PER_SECOND: u32 = 75;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of `INDEX 01`, in milliseconds from the start of the track's file.
+    pub start: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CueSheet {
+    pub files: Vec<CueFile>,
+}
+
+/// Split a CUE line into its command and arguments, keeping quoted arguments together.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut token = String::new();
+
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<u32> {
+    let mut parts = timestamp.split(':').map(str::parse::<u32>);
+    let (minutes, seconds, frames) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        return None;
+    }
+
+    let frames = (minutes.checked_mul(60)?.checked_add(seconds)?)
+        .checked_mul(FRAMES_PER_SECOND)?
+        .checked_add(frames)?;
+    let ms = (frames as f64 * 1000.0 / FRAMES_PER_SECOND as f64).round();
+
+    (ms <= u32::MAX as f64).then_some(ms as u32)
+}
+
+impl CueSheet {
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut sheet = Self::default();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line_number = i + 1;
+            let tokens = tokenize(line);
+            let Some((command, args)) = tokens.split_first() else {
+                continue;
+            };
+
+            // A track's pregap can be in the previous file, so its INDEX 01 can come after the next
+            // FILE line, before any TRACK in it.
+            let track = sheet
+                .files
+                .iter_mut()
+                .rev()
+                .find_map(|file| file.tracks.last_mut());
+
+            match (command.to_ascii_uppercase().as_str(), args, track) {
+                ("FILE", [name, ..], _) => sheet.files.push(CueFile {
+                    name: name.clone(),
+                    tracks: Vec::new(),
+                }),
+                ("TRACK", [number, ..], _) => {
+                    let number = number
+                        .parse()
+                        .with_context(|| format!("invalid track number on line {line_number}"))?;
+
+                    sheet
+                        .files
+                        .last_mut()
+                        .with_context(|| format!("TRACK before any FILE on line {line_number}"))?
+                        .tracks
+                        .push(CueTrack {
+                            number,
+                            ..CueTrack::default()
+                        });
+                }
+                ("TITLE", [title, ..], Some(track)) => track.title = Some(title.clone()),
+                ("PERFORMER", [performer, ..], Some(track)) => {
+                    track.performer = Some(performer.clone())
+                }
+                ("INDEX", [index, timestamp, ..], Some(_)) if index.parse() == Ok(1) => {
+                    let start = parse_timestamp(timestamp).with_context(|| {
+                        format!("invalid timestamp '{timestamp}' on line {line_number}")
+                    })?;
+
+                    // The track belongs to the file it starts in, not the one with its pregap.
+                    let current = sheet.files.len() - 1;
+                    let owner = (sheet.files.iter()).rposition(|file| !file.tracks.is_empty());
+
+                    if let Some(owner) = owner.filter(|&owner| owner != current) {
+                        let moved = sheet.files[owner].tracks.pop();
+                        sheet.files[current].tracks.extend(moved);
+                    }
+
+                    if let Some(track) = sheet.files[current].tracks.last_mut() {
+                        track.start = Some(start);
+                    }
+                }
+                // Disc-level TITLE/PERFORMER, REM comments, and everything else we don't need.
+                _ => {}
+            }
+        }
+
+        Ok(sheet)
+    }
+
+    pub fn read_from_path(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path)?;
+        let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+
+        // Plenty of CUE sheets are still written in Latin-1 rather than UTF-8.
+        let contents = match std::str::from_utf8(bytes) {
+            Ok(contents) => contents.to_string(),
+            Err(_) => bytes.iter().map(|&b| b as char).collect(),
+        };
+
+        Self::parse(&contents)
+    }
+
+    /// Make sure the sheet's `FILE`s can be matched to the inputs in order: there has to be one
+    /// per input, and any that exist next to the sheet have to have the same name as the input
+    /// in their position.
+    pub fn check_inputs(&self, path: &Path, inputs: &[impl AsRef<Path>]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.files.len() == inputs.len(),
+            "CUE sheet references {} files, but {} input files were given",
+            self.files.len(),
+            inputs.len()
+        );
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        for (file, input) in self.files.iter().zip(inputs) {
+            // Sheets often name the original rip, which needn't be among the inputs at all.
+            let named = dir.join(&file.name);
+            if !named.is_file() {
+                continue;
+            }
+
+            let input = input.as_ref();
+            anyhow::ensure!(
+                named.file_name() == input.file_name(),
+                "CUE sheet's FILE '{}' is in the position of input file '{}', check the order \
+                 of the inputs",
+                file.name,
+                input.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Build one chapter per track, with each `FILE` in the sheet matched to an input in order.
+    ///
+    /// Chapters are grouped by the input file they belong to.
+    pub fn chapters(&self, probes: &[Probe]) -> anyhow::Result<Vec<Vec<Chapter>>> {
+        anyhow::ensure!(
+            self.files.len() == probes.len(),
+            "CUE sheet references {} files, but {} input files were given",
+            self.files.len(),
+            probes.len()
+        );
+
+        let mut chapters = Vec::with_capacity(self.files.len());
+        let mut chapter_count = 0;
+        let mut file_start: u32 = 0;
+
+        for (file, probe) in self.files.iter().zip(probes) {
+            let mut file_chapters = Vec::with_capacity(file.tracks.len());
+            let file_end = file_start + (probe.duration_secs * 1000.0).round() as u32;
+
+            for (i, track) in file.tracks.iter().enumerate() {
+                let start = track.start.with_context(|| {
+                    format!("track {} in CUE sheet has no INDEX 01", track.number)
+                })?;
+                let end = match file.tracks.get(i + 1) {
+                    Some(next) => next.start.unwrap_or(start),
+                    None => file_end - file_start,
+                };
+
+                anyhow::ensure!(
+                    start <= end && end <= file_end - file_start,
+                    "track {} in CUE sheet is out of bounds for file '{}'",
+                    track.number,
+                    file.name
+                );
+
+                // We don't know where the track falls within the file's bytes.
+                let mut chapter = Chapter {
+                    element_id: format!("chapter_{chapter_count}"),
+                    start_time: file_start + start,
+                    end_time: file_start + end,
+                    start_offset: UNUSED_OFFSET,
+                    end_offset: UNUSED_OFFSET,
+                    frames: vec![],
+                };
+
+                chapter.set_title(match &track.title {
+                    Some(title) => title.clone(),
+                    None => format!("Track {:02}", track.number),
+                });
+
+                if let Some(performer) = &track.performer {
+                    chapter.set_artist(performer);
+                }
+
+                file_chapters.push(chapter);
+                chapter_count += 1;
+            }
+
+            chapters.push(file_chapters);
+            file_start = file_end;
+        }
+
+        Ok(chapters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::probe;
+
+    fn times(chapters: &[Vec<Chapter>]) -> Vec<Vec<(u32, u32)>> {
+        (chapters.iter())
+            .map(|file| (file.iter()).map(|c| (c.start_time, c.end_time)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_tracks_per_file() {
+        let sheet = CueSheet::parse(
+            r#"
+            PERFORMER "Author"
+            TITLE "Book"
+            FILE "one.mp3" MP3
+              TRACK 01 AUDIO
+                TITLE "Opening"
+                PERFORMER "Narrator"
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                TITLE "Chapter 1"
+                INDEX 00 01:59:00
+                INDEX 01 02:00:37
+            FILE "two.mp3" MP3
+              TRACK 03 AUDIO
+                INDEX 01 00:00:00
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(sheet.files.len(), 2);
+        assert_eq!(sheet.files[0].name, "one.mp3");
+        assert_eq!(
+            sheet.files[0].tracks,
+            [
+                CueTrack {
+                    number: 1,
+                    title: Some(String::from("Opening")),
+                    performer: Some(String::from("Narrator")),
+                    start: Some(0),
+                },
+                CueTrack {
+                    number: 2,
+                    title: Some(String::from("Chapter 1")),
+                    performer: None,
+                    start: Some(120_493),
+                },
+            ]
+        );
+        assert_eq!(sheet.files[1].tracks[0].number, 3);
+    }
+
+    #[test]
+    fn moves_tracks_to_the_file_with_their_index_01() {
+        // As written by EAC, with each track's pregap at the end of the previous file.
+        let sheet = CueSheet::parse(
+            r#"
+            FILE "01.flac" WAVE
+              TRACK 01 AUDIO
+                TITLE "One"
+                INDEX 01 00:00:00
+              TRACK 02 AUDIO
+                TITLE "Two"
+                INDEX 00 00:58:00
+            FILE "02.flac" WAVE
+                INDEX 01 00:00:00
+              TRACK 03 AUDIO
+                TITLE "Three"
+                INDEX 01 00:30:00
+            "#,
+        )
+        .unwrap();
+
+        let numbers: Vec<Vec<_>> = (sheet.files.iter())
+            .map(|file| file.tracks.iter().map(|track| track.number).collect())
+            .collect();
+        assert_eq!(numbers, [vec![1], vec![2, 3]]);
+
+        let chapters = sheet
+            .chapters(&[probe("mp3", 60.0), probe("mp3", 45.0)])
+            .unwrap();
+        assert_eq!(
+            times(&chapters),
+            [vec![(0, 60_000)], vec![(60_000, 90_000), (90_000, 105_000)]]
+        );
+        assert_eq!(chapters[1][0].title(), Some("Two"));
+    }
+
+    #[test]
+    fn rejects_timestamps_out_of_range() {
+        assert_eq!(parse_timestamp("01:02:03"), Some(62_040));
+        assert_eq!(parse_timestamp("900000:00:00"), None);
+        assert_eq!(parse_timestamp("99999999:00:00"), None);
+        assert_eq!(parse_timestamp("00:60:00"), None);
+        assert_eq!(parse_timestamp("00:00:75"), None);
+
+        let err = CueSheet::parse("FILE \"a.mp3\" MP3\nTRACK 01 AUDIO\nINDEX 01 99999999:00:00")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid timestamp '99999999:00:00' on line 3"
+        );
+    }
+
+    #[test]
+    fn checks_files_that_exist_against_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let sheet_path = dir.path().join("book.cue");
+        std::fs::write(dir.path().join("01.mp3"), b"").unwrap();
+        std::fs::write(dir.path().join("02.mp3"), b"").unwrap();
+
+        let sheet =
+            CueSheet::parse("FILE \"01.mp3\" MP3\nFILE \"02.mp3\" MP3\nFILE \"03.wav\" WAVE")
+                .unwrap();
+        let inputs = ["01.mp3", "02.mp3", "03.mp3"].map(|name| dir.path().join(name));
+        sheet.check_inputs(&sheet_path, &inputs).unwrap();
+
+        // As if sorted by tags into a different order than the sheet's.
+        let swapped = ["02.mp3", "01.mp3", "03.mp3"].map(|name| dir.path().join(name));
+        let err = sheet.check_inputs(&sheet_path, &swapped).unwrap_err();
+        assert!(
+            err.to_string().starts_with("CUE sheet's FILE '01.mp3'"),
+            "{err}"
+        );
+
+        let err = sheet.check_inputs(&sheet_path, &inputs[..2]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "CUE sheet references 3 files, but 2 input files were given"
+        );
+    }
+
+    #[test]
+    fn chapters_need_index_01() {
+        let sheet =
+            CueSheet::parse("FILE \"a.mp3\" MP3\nTRACK 01 AUDIO\nINDEX 00 00:00:00").unwrap();
+        let err = sheet.chapters(&[probe("mp3", 10.0)]).unwrap_err();
+        assert_eq!(err.to_string(), "track 1 in CUE sheet has no INDEX 01");
+    }
+
+    #[test]
+    fn chapters_must_fit_their_file() {
+        let sheet =
+            CueSheet::parse("FILE \"a.mp3\" MP3\nTRACK 01 AUDIO\nINDEX 01 00:20:00").unwrap();
+        assert!(sheet.chapters(&[probe("mp3", 10.0)]).is_err());
+    }
+}
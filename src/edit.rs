@@ -0,0 +1,455 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use id3::{frame::Chapter, Tag, TagLike};
+
+use crate::{
+    backend::{Backend, FfmpegBackend},
+    chapters::{self, ChapterOffsets, UNUSED_OFFSET},
+    metadata::{self, Metadata},
+    mp3,
+    timestamp::format_timestamp,
+    timing,
+};
+
+/// A change to the chapters of an existing file.
+///
+/// Chapter numbers start from 1 and always refer to the chapters as they were before editing,
+/// ordered by start time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChapterEdit {
+    /// Change the title of a chapter.
+    Rename(usize, String),
+    /// Remove a chapter, extending the previous chapter (or the next, for the first) over it.
+    Delete(usize),
+    /// Move the start of a chapter by some milliseconds, along with the end of the chapter before.
+    Shift(usize, i64),
+    /// Add a new chapter starting at the given time, splitting the chapter it falls in.
+    Insert(u32, String),
+}
+
+/// Builder for retagging and re-chaptering an existing MP3 file without touching its audio.
+#[derive(Clone, Debug)]
+pub struct Editor {
+    path: PathBuf,
+    metadata: Metadata,
+    chapter_edits: Vec<ChapterEdit>,
+    chapter_offsets: ChapterOffsets,
+    backend: Arc<dyn Backend>,
+}
+
+impl Editor {
+    /// Create a new edit of the given file.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            metadata: Metadata::default(),
+            chapter_edits: Vec::new(),
+            chapter_offsets: ChapterOffsets::default(),
+            backend: Arc::new(FfmpegBackend),
+        }
+    }
+
+    /// Set global tag fields, replacing the existing values of any fields that are set.
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Add a change to the chapters.
+    pub fn chapter_edit(mut self, edit: ChapterEdit) -> Self {
+        self.chapter_edits.push(edit);
+        self
+    }
+
+    /// How to fill in chapter byte offsets once the chapters are edited. Defaults to the
+    /// positions of the frames each chapter starts and ends at.
+    pub fn chapter_offsets(mut self, offsets: ChapterOffsets) -> Self {
+        self.chapter_offsets = offsets;
+        self
+    }
+
+    /// The backend used to find the file's frames, if it can't be scanned natively. Defaults to
+    /// [`FfmpegBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Apply the chapter edits to `chapters`, sorted by start time. Only chapter times change;
+    /// byte offsets are left for the caller to fill in.
+    fn edit_chapters(&self, chapters: Vec<Chapter>) -> anyhow::Result<Vec<Chapter>> {
+        let count = chapters.len();
+        let old_ids: Vec<_> = chapters
+            .iter()
+            .map(|chapter| chapter.element_id.clone())
+            .collect();
+        let mut chapters: Vec<_> = chapters.into_iter().map(Some).collect();
+        let mut inserts = Vec::new();
+
+        let check = |number: usize| {
+            anyhow::ensure!(
+                (1..=count).contains(&number),
+                "there is no chapter {number}, the file has {count} chapters"
+            );
+            Ok(number - 1)
+        };
+
+        for edit in &self.chapter_edits {
+            match edit {
+                ChapterEdit::Rename(number, title) => {
+                    if let Some(chapter) = &mut chapters[check(*number)?] {
+                        chapter.set_title(title);
+                    }
+                }
+                ChapterEdit::Shift(number, offset) => {
+                    let i = check(*number)?;
+                    let Some(chapter) = &chapters[i] else {
+                        continue;
+                    };
+
+                    let old_start = chapter.start_time;
+                    let new_start = u32::try_from(old_start as i64 + offset)
+                        .ok()
+                        .filter(|&start| start < chapter.end_time)
+                        .with_context(|| format!("can't shift chapter {number} by {offset} ms"))?;
+
+                    let previous = chapters[..i].iter_mut().rev().flatten().next();
+
+                    if let Some(previous) = previous {
+                        anyhow::ensure!(
+                            new_start > previous.start_time,
+                            "can't shift chapter {number} before the start of the chapter before it"
+                        );
+
+                        if previous.end_time == old_start || previous.end_time > new_start {
+                            previous.end_time = new_start;
+                        }
+                    }
+
+                    chapters[i].as_mut().unwrap().start_time = new_start;
+                }
+                ChapterEdit::Delete(number) => {
+                    let i = check(*number)?;
+                    let Some(deleted) = chapters[i].take() else {
+                        continue;
+                    };
+
+                    let previous = chapters[..i].iter_mut().rev().flatten().next();
+
+                    if let Some(previous) = previous {
+                        previous.end_time = previous.end_time.max(deleted.end_time);
+                    } else if let Some(next) = chapters[i..].iter_mut().flatten().next() {
+                        next.start_time = next.start_time.min(deleted.start_time);
+                    }
+                }
+                ChapterEdit::Insert(start, title) => inserts.push((*start, title)),
+            }
+        }
+
+        let mut chapters: Vec<_> = chapters.into_iter().flatten().collect();
+        let mut next_id = 0;
+
+        for (start, title) in inserts {
+            // Splitting a chapter at its own start would leave it empty.
+            if let Some(existing) = chapters.iter().find(|chapter| chapter.start_time == start) {
+                anyhow::bail!(
+                    "can't insert a chapter at {}, chapter '{}' already starts there",
+                    format_timestamp(start),
+                    existing.title().unwrap_or(&existing.element_id)
+                );
+            }
+
+            let i = chapters
+                .iter()
+                .position(|chapter| (chapter.start_time..chapter.end_time).contains(&start))
+                .with_context(|| {
+                    format!(
+                        "can't insert a chapter at {}, it's not within any chapter",
+                        format_timestamp(start)
+                    )
+                })?;
+
+            // Inserted chapters get IDs that weren't in the file, even for since-deleted chapters,
+            // so they can be told apart when updating the tables of contents.
+            while old_ids.contains(&format!("chapter_{next_id}")) {
+                next_id += 1;
+            }
+
+            let element_id = format!("chapter_{next_id}");
+            next_id += 1;
+
+            let split = &mut chapters[i];
+            let mut chapter = Chapter {
+                element_id,
+                start_time: start,
+                end_time: split.end_time,
+                start_offset: UNUSED_OFFSET,
+                end_offset: UNUSED_OFFSET,
+                frames: Vec::new(),
+            };
+            chapter.set_title(title);
+
+            split.end_time = start;
+            chapters.insert(i + 1, chapter);
+        }
+
+        Ok(chapters)
+    }
+
+    /// Apply the changes and write the tag back to the file.
+    pub fn edit(&self) -> anyhow::Result<()> {
+        let display = self.path.display();
+
+        // Chapter offsets in the tag count from the start of the file, so from before the old tag.
+        let audio_start = mp3::audio_start(&self.path)
+            .with_context(|| format!("failed to find start of audio in '{display}'"))?;
+        let mut offsets_from = audio_start;
+
+        let mut tag = Tag::read_from_path(&self.path)
+            .with_context(|| format!("failed to read ID3 tag from '{display}'"))?;
+
+        metadata::populate_metadata(&self.metadata, &mut tag, Vec::new(), Vec::new())
+            .context("failed to set ID3 metadata")?;
+
+        if !self.chapter_edits.is_empty() {
+            let mut chapters: Vec<_> = tag.chapters().cloned().collect();
+            chapters.sort_by_key(|chapter| chapter.start_time);
+
+            let old_ids: Vec<_> = chapters
+                .iter()
+                .map(|chapter| chapter.element_id.clone())
+                .collect();
+            let mut chapters = self
+                .edit_chapters(chapters)
+                .context("failed to edit chapters")?;
+
+            // Every chapter gets new offsets, the same way merging and appending set them.
+            match self.chapter_offsets {
+                ChapterOffsets::Frames => {
+                    let stream = timing::MergedStream::measure(&*self.backend, &self.path)
+                        .with_context(|| format!("failed to find the frames of '{display}'"))?;
+                    stream.set_offsets(&mut chapters);
+                    offsets_from = 0;
+                }
+                ChapterOffsets::Unused => {
+                    for chapter in &mut chapters {
+                        chapter.start_offset = UNUSED_OFFSET;
+                        chapter.end_offset = UNUSED_OFFSET;
+                    }
+                }
+            }
+
+            // Keep the tables of contents in sync: drop deleted chapters, and list inserted ones
+            // right after the chapter they were split from.
+            let mut tables_of_contents: Vec<_> = tag.tables_of_contents().cloned().collect();
+
+            for toc in &mut tables_of_contents {
+                let mut elements = Vec::with_capacity(toc.elements.len());
+
+                for element in &toc.elements {
+                    let Some(position) = chapters.iter().position(|c| c.element_id == *element)
+                    else {
+                        if !old_ids.contains(element) {
+                            elements.push(element.clone());
+                        }
+
+                        continue;
+                    };
+
+                    elements.push(element.clone());
+                    elements.extend(
+                        chapters[position + 1..]
+                            .iter()
+                            .map(|chapter| &chapter.element_id)
+                            .take_while(|id| !old_ids.contains(id))
+                            .cloned(),
+                    );
+                }
+
+                toc.elements = elements;
+            }
+
+            tag.remove_all_chapters();
+            tag.remove_all_tables_of_contents();
+
+            for toc in tables_of_contents {
+                tag.add_frame(toc);
+            }
+
+            for chapter in chapters {
+                tag.add_frame(chapter);
+            }
+        }
+
+        // The tag is written in place if it still fits in the space the old one took up, and
+        // otherwise moves the audio along with it.
+        chapters::rebase_offsets(&mut tag, offsets_from, audio_start)?;
+
+        metadata::write_tag(&tag, &self.path, audio_start)
+            .with_context(|| format!("failed to write ID3 metadata to '{display}'"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(id: usize, start_time: u32, end_time: u32) -> Chapter {
+        let mut chapter = Chapter {
+            element_id: format!("chapter_{id}"),
+            start_time,
+            end_time,
+            start_offset: UNUSED_OFFSET,
+            end_offset: UNUSED_OFFSET,
+            frames: Vec::new(),
+        };
+        chapter.set_title(format!("Chapter {}", id + 1));
+        chapter
+    }
+
+    /// Three chapters: 0-10s, 10-20s and 20-30s.
+    fn three_chapters() -> Vec<Chapter> {
+        vec![
+            chapter(0, 0, 10_000),
+            chapter(1, 10_000, 20_000),
+            chapter(2, 20_000, 30_000),
+        ]
+    }
+
+    fn edit(edits: impl IntoIterator<Item = ChapterEdit>) -> anyhow::Result<Vec<Chapter>> {
+        let editor = (edits.into_iter()).fold(Editor::new("book.mp3"), Editor::chapter_edit);
+        editor.edit_chapters(three_chapters())
+    }
+
+    fn summary(chapters: &[Chapter]) -> Vec<(&str, &str, u32, u32)> {
+        (chapters.iter())
+            .map(|chapter| {
+                let title = chapter.title().unwrap_or_default();
+                (
+                    &*chapter.element_id,
+                    title,
+                    chapter.start_time,
+                    chapter.end_time,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn numbers_refer_to_chapters_before_editing() {
+        let chapters = edit([
+            ChapterEdit::Delete(1),
+            ChapterEdit::Rename(2, String::from("Two")),
+            ChapterEdit::Insert(25_000, String::from("Late")),
+            ChapterEdit::Shift(3, 1000),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            summary(&chapters),
+            [
+                ("chapter_1", "Two", 0, 21_000),
+                ("chapter_2", "Chapter 3", 21_000, 25_000),
+                ("chapter_3", "Late", 25_000, 30_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn deleting_extends_the_previous_chapter_or_the_next_for_the_first() {
+        let chapters = edit([ChapterEdit::Delete(2)]).unwrap();
+        assert_eq!(
+            summary(&chapters),
+            [
+                ("chapter_0", "Chapter 1", 0, 20_000),
+                ("chapter_2", "Chapter 3", 20_000, 30_000),
+            ]
+        );
+
+        let chapters = edit([ChapterEdit::Delete(1), ChapterEdit::Delete(2)]).unwrap();
+        assert_eq!(summary(&chapters), [("chapter_2", "Chapter 3", 0, 30_000)]);
+
+        // Deleting the same chapter twice only deletes it once.
+        let chapters = edit([ChapterEdit::Delete(3), ChapterEdit::Delete(3)]).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].end_time, 30_000);
+    }
+
+    #[test]
+    fn shifting_moves_the_end_of_the_previous_chapter() {
+        let chapters = edit([ChapterEdit::Shift(2, -2500)]).unwrap();
+        assert_eq!(
+            summary(&chapters),
+            [
+                ("chapter_0", "Chapter 1", 0, 7500),
+                ("chapter_1", "Chapter 2", 7500, 20_000),
+                ("chapter_2", "Chapter 3", 20_000, 30_000),
+            ]
+        );
+
+        let chapters = edit([ChapterEdit::Shift(1, 500)]).unwrap();
+        assert_eq!(chapters[0].start_time, 500);
+    }
+
+    #[test]
+    fn shifts_stay_within_neighbouring_chapters() {
+        let messages = [
+            (ChapterEdit::Shift(1, -1), "can't shift chapter 1 by -1 ms"),
+            (
+                ChapterEdit::Shift(2, 10_000),
+                "can't shift chapter 2 by 10000 ms",
+            ),
+            (
+                ChapterEdit::Shift(2, -10_000),
+                "can't shift chapter 2 before the start of the chapter before it",
+            ),
+            (
+                ChapterEdit::Shift(4, 0),
+                "there is no chapter 4, the file has 3 chapters",
+            ),
+        ];
+
+        for (shift, message) in messages {
+            assert_eq!(edit([shift]).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn inserting_splits_a_chapter_with_a_new_id() {
+        // Chapter 1's ID isn't reused, even though it's gone.
+        let chapters = edit([
+            ChapterEdit::Delete(2),
+            ChapterEdit::Insert(5000, String::from("Half")),
+            ChapterEdit::Insert(12_000, String::from("Later")),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            summary(&chapters),
+            [
+                ("chapter_0", "Chapter 1", 0, 5000),
+                ("chapter_3", "Half", 5000, 12_000),
+                ("chapter_4", "Later", 12_000, 20_000),
+                ("chapter_2", "Chapter 3", 20_000, 30_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn inserts_need_to_start_inside_a_chapter() {
+        let err = edit([ChapterEdit::Insert(0, String::from("Zero"))]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't insert a chapter at 00:00:00.000, chapter 'Chapter 1' already starts there"
+        );
+
+        let err = edit([ChapterEdit::Insert(30_000, String::from("End"))]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't insert a chapter at 00:00:30.000, it's not within any chapter"
+        );
+    }
+}
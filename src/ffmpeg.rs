@@ -0,0 +1,55 @@
+use std::{ffi::OsString, time::Duration};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::interrupt;
+
+/// Create a bar for showing how far into `total_secs` of output ffmpeg has got, along with its
+/// speed and the time left.
+pub(crate) fn progress_bar(total_secs: f64, message: &str) -> anyhow::Result<ProgressBar> {
+    let total_ms = (total_secs.max(0.0) * 1000.0).round() as u64;
+    let progress_bar = ProgressBar::new(total_ms.max(1))
+        .with_style(
+            ProgressStyle::default_bar()
+                .template("[{percent:>3}%] {spinner} {msg} [{bar:30}] {eta} left")?
+                .progress_chars("=> "),
+        )
+        .with_message(message.to_string());
+    progress_bar.enable_steady_tick(Duration::from_millis(100));
+
+    Ok(progress_bar)
+}
+
+/// Move a bar from [`progress_bar`] to `secs` into the output, with the speed so far after
+/// `message`.
+pub(crate) fn set_progress(progress_bar: &ProgressBar, message: &str, secs: f64) {
+    let ms = (secs.max(0.0) * 1000.0).round() as u64;
+    progress_bar.set_position(ms.min(progress_bar.length().unwrap_or(ms)));
+
+    // Like ffmpeg's own, the speed is how much output there is for the time taken.
+    let elapsed = progress_bar.elapsed().as_secs_f64();
+    if elapsed >= 1.0 {
+        progress_bar.set_message(format!("{message} ({:.1}x)", secs / elapsed));
+    }
+}
+
+/// Run ffmpeg, calling `progress` with how many seconds of output it has written as it goes.
+pub(crate) fn run(args: Vec<OsString>, progress: &mut dyn FnMut(f64)) -> anyhow::Result<()> {
+    // ffmpeg writes `key=value` lines to stdout, with a block ending in `progress=...` every
+    // half a second or so. Options after the output path would be ignored.
+    let mut all_args: Vec<OsString> = ["-progress", "pipe:1", "-nostats"].map(Into::into).into();
+    all_args.extend(args);
+
+    interrupt::for_each_line(duct::cmd("ffmpeg", all_args), |line| {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            return;
+        };
+
+        // Despite its name, `out_time_ms` is in microseconds too.
+        if let ("out_time_us" | "out_time_ms", Ok(us)) = (key, value.parse::<u64>()) {
+            progress(us as f64 / 1_000_000.0);
+        }
+    })?;
+
+    Ok(())
+}
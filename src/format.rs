@@ -0,0 +1,35 @@
+use std::{path::Path, str::FromStr};
+
+/// Container and codec of the merged output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// MP3 audio with an ID3v2.4 tag and `CHAP` frames.
+    Mp3,
+    /// AAC audio in an MP4 container, with iTunes metadata atoms and MP4 chapters.
+    M4b,
+}
+
+impl Format {
+    /// File extensions (and format names) we know how to write.
+    pub const EXTENSIONS: [&'static str; 3] = ["mp3", "m4b", "m4a"];
+
+    /// Guess the output format from a file extension, if it's one we know how to write.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        path.as_ref().extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "mp3" => Ok(Self::Mp3),
+            "m4b" | "m4a" => Ok(Self::M4b),
+            _ => Err(format!(
+                "unsupported format '{name}', expected one of: {}",
+                Self::EXTENSIONS.join(", ")
+            )),
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::{
+    backend::Backend,
+    probe::{self, Probe},
+};
+
+/// How to order the files found in a directory or matched by a glob pattern.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputOrder {
+    /// By path, comparing runs of digits as numbers, so `2.mp3` comes before `10.mp3`.
+    #[default]
+    Natural,
+    /// By the track number tag.
+    Track,
+    /// By the disc number tag, then the track number tag.
+    DiscTrack,
+    /// By modification time, oldest first.
+    Modified,
+}
+
+impl FromStr for InputOrder {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "natural" => Ok(Self::Natural),
+            "track" => Ok(Self::Track),
+            "disc-track" => Ok(Self::DiscTrack),
+            "modified" => Ok(Self::Modified),
+            _ => Err(format!(
+                "unknown input order '{name}', expected natural, track, disc-track or modified"
+            )),
+        }
+    }
+}
+
+/// Compare two strings, treating runs of ASCII digits as numbers and ignoring case.
+///
+/// Strings that only differ in case or leading zeros are ordered by the first such difference, so
+/// the order is still total.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    let mut tie = Ordering::Equal;
+
+    loop {
+        let (Some(a_char), Some(b_char)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len()).then(tie);
+        };
+
+        let ordering = if a_char.is_ascii_digit() && b_char.is_ascii_digit() {
+            let a_len = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let b_len = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (a_digits, b_digits) = (&a[..a_len], &b[..b_len]);
+            let (a_trimmed, b_trimmed) = (
+                a_digits.trim_start_matches('0'),
+                b_digits.trim_start_matches('0'),
+            );
+
+            a = &a[a_len..];
+            b = &b[b_len..];
+            tie = tie.then_with(|| a_digits.len().cmp(&b_digits.len()));
+
+            a_trimmed
+                .len()
+                .cmp(&b_trimmed.len())
+                .then_with(|| a_trimmed.cmp(b_trimmed))
+        } else {
+            a = &a[a_char.len_utf8()..];
+            b = &b[b_char.len_utf8()..];
+            tie = tie.then_with(|| a_char.cmp(&b_char));
+
+            a_char.to_lowercase().cmp(b_char.to_lowercase())
+        };
+
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    mime_guess::from_path(path)
+        .iter()
+        .any(|mime| mime.type_() == mime_guess::mime::AUDIO)
+}
+
+fn walk_directory(directory: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries = fs::read_dir(directory)
+        .with_context(|| format!("failed to read input directory '{}'", directory.display()))?;
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Links to directories aren't followed, since they could lead back up the tree, but links
+        // to files are taken like any other file.
+        if entry.file_type()?.is_dir() {
+            walk_directory(&path, files)?;
+        } else if !path.is_dir() && is_audio(&path) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Expand directories and glob patterns into the files they contain.
+///
+/// Each argument turns into a group of files, naturally sorted if there's more than one. The
+/// returned list pairs each file with the index of the argument it came from.
+pub(crate) fn expand_inputs(inputs: &[impl AsRef<Path>]) -> anyhow::Result<Vec<(usize, PathBuf)>> {
+    let mut expanded = Vec::with_capacity(inputs.len());
+
+    for (group, input) in inputs.iter().map(AsRef::as_ref).enumerate() {
+        let mut files = Vec::new();
+        let pattern = input.to_string_lossy();
+
+        if input.is_dir() {
+            walk_directory(input, &mut files)?;
+        } else if !input.exists() && pattern.contains(['*', '?', '[']) {
+            for path in
+                glob::glob(&pattern).with_context(|| format!("invalid glob pattern '{pattern}'"))?
+            {
+                files.push(path?);
+            }
+        } else {
+            files.push(input.to_owned());
+        }
+
+        anyhow::ensure!(
+            !files.is_empty(),
+            "no audio files found for input '{pattern}'"
+        );
+
+        files.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        expanded.extend(files.into_iter().map(|path| (group, path)));
+    }
+
+    Ok(expanded)
+}
+
+// Tags like "3/12" mean number 3 of 12.
+fn tag_number(probe: &Probe, name: &str) -> Option<u32> {
+    let value = probe.tags.get(name)?;
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Reorder files within each input group, keeping the natural order for ties.
+///
+/// Files missing the tag being sorted on go after the ones that have it.
+pub(crate) fn sort_inputs(
+    inputs: Vec<(usize, PathBuf)>,
+    probes: Vec<Probe>,
+    order: InputOrder,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<Probe>)> {
+    let mut keyed = Vec::with_capacity(inputs.len());
+
+    for ((group, path), probe) in inputs.into_iter().zip(probes) {
+        let key: (Option<u32>, Option<u32>, Option<SystemTime>) = match order {
+            InputOrder::Natural => (None, None, None),
+            InputOrder::Track => (None, tag_number(&probe, "track"), None),
+            InputOrder::DiscTrack => (
+                tag_number(&probe, "disc"),
+                tag_number(&probe, "track"),
+                None,
+            ),
+            InputOrder::Modified => (
+                None,
+                None,
+                Some(
+                    fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .with_context(|| {
+                            format!("failed to get modification time of '{}'", path.display())
+                        })?,
+                ),
+            ),
+        };
+
+        keyed.push((group, key, path, probe));
+    }
+
+    // `None` sorts before `Some`, so flip it around to put missing tags last.
+    keyed.sort_by_key(|(group, (disc, track, modified), _, _)| {
+        (
+            *group,
+            disc.is_none(),
+            *disc,
+            track.is_none(),
+            *track,
+            *modified,
+        )
+    });
+
+    Ok(keyed
+        .into_iter()
+        .map(|(_, _, path, probe)| (path, probe))
+        .unzip())
+}
+
+/// Probe (up to `jobs` at a time) and sort inputs from [`expand_inputs`], returning the files to
+/// merge along with their probes.
+pub(crate) fn collect_inputs(
+    backend: &dyn Backend,
+    inputs: Vec<(usize, PathBuf)>,
+    order: InputOrder,
+    jobs: usize,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<Probe>)> {
+    let paths: Vec<_> = inputs.iter().map(|(_, path)| path).collect();
+    let probes =
+        probe::probe_inputs(backend, &paths, jobs).context("failed to probe input files")?;
+
+    sort_inputs(inputs, probes, order).context("failed to sort input files")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::tagged_probe;
+
+    #[test]
+    fn compares_numbers_naturally() {
+        let mut names = [
+            "10.mp3",
+            "2.mp3",
+            "Disc 2/01.mp3",
+            "disc 1/10.mp3",
+            "Disc 1/9.mp3",
+            "02.mp3",
+            "b.mp3",
+            "B.mp3",
+            "1.mp3",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            [
+                "1.mp3",
+                "2.mp3",
+                "02.mp3",
+                "10.mp3",
+                "B.mp3",
+                "b.mp3",
+                "Disc 1/9.mp3",
+                "disc 1/10.mp3",
+                "Disc 2/01.mp3",
+            ]
+        );
+        assert_eq!(natural_cmp("track", "track 1"), Ordering::Less);
+        assert_eq!(natural_cmp("track 007", "track 007"), Ordering::Equal);
+    }
+
+    #[test]
+    fn sorts_by_tags_within_groups() {
+        let inputs = ["a.mp3", "b.mp3", "c.mp3", "d.mp3", "e.mp3", "f.mp3"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (i / 4, PathBuf::from(name)))
+            .collect::<Vec<_>>();
+        let probes = vec![
+            tagged_probe("mp3", &[("track", "3/4"), ("disc", "1")]),
+            tagged_probe("mp3", &[]),
+            tagged_probe("mp3", &[("track", "1"), ("disc", "2")]),
+            tagged_probe("mp3", &[("track", "1"), ("disc", "1")]),
+            tagged_probe("mp3", &[("track", "2")]),
+            tagged_probe("mp3", &[("track", "1")]),
+        ];
+
+        let sort = |order| {
+            let (paths, _) = sort_inputs(inputs.clone(), probes.clone(), order).unwrap();
+            paths
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect::<String>()
+        };
+
+        // Files stay within the group they came from, with missing tags last and ties in the
+        // order they were given in.
+        assert_eq!(sort(InputOrder::Natural), "a.mp3b.mp3c.mp3d.mp3e.mp3f.mp3");
+        assert_eq!(sort(InputOrder::Track), "c.mp3d.mp3a.mp3b.mp3f.mp3e.mp3");
+        assert_eq!(
+            sort(InputOrder::DiscTrack),
+            "d.mp3a.mp3c.mp3b.mp3f.mp3e.mp3"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_links_to_directories() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let disc = dir.path().join("disc 1");
+        fs::create_dir(&disc).unwrap();
+        fs::write(disc.join("01.mp3"), b"").unwrap();
+        fs::write(disc.join("cover.jpg"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path(), disc.join("loop")).unwrap();
+        std::os::unix::fs::symlink(disc.join("01.mp3"), dir.path().join("02.mp3")).unwrap();
+
+        let expanded = expand_inputs(&[dir.path()]).unwrap();
+        assert_eq!(
+            expanded,
+            [(0, dir.path().join("02.mp3")), (0, disc.join("01.mp3"))]
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use std::{fmt, path::Path};
+
+use anyhow::Context;
+use id3::{
+    frame::{Chapter, TableOfContents},
+    Content, Frame, Tag, TagLike,
+};
+use serde::Serialize;
+
+use crate::{chapters::UNUSED_OFFSET, timestamp::format_timestamp};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TextFrame {
+    pub id: String,
+    pub values: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PictureInfo {
+    pub picture_type: String,
+    pub mime_type: String,
+    pub description: String,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommentInfo {
+    pub lang: String,
+    pub description: String,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChapterInfo {
+    pub element_id: String,
+    pub title: Option<String>,
+    pub start_time: u32,
+    pub end_time: u32,
+    pub start: String,
+    pub end: String,
+    /// `None` if the offset is the "unused" value.
+    pub start_offset: Option<u32>,
+    pub end_offset: Option<u32>,
+    pub frames: Vec<TextFrame>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TableOfContentsInfo {
+    pub element_id: String,
+    pub title: Option<String>,
+    pub top_level: bool,
+    pub ordered: bool,
+    pub elements: Vec<String>,
+}
+
+/// Everything of interest in the ID3 tag of a file.
+#[derive(Clone, Debug, Serialize)]
+pub struct Inspection {
+    pub version: String,
+    pub text_frames: Vec<TextFrame>,
+    pub pictures: Vec<PictureInfo>,
+    pub comments: Vec<CommentInfo>,
+    pub tables_of_contents: Vec<TableOfContentsInfo>,
+    pub chapters: Vec<ChapterInfo>,
+    /// IDs of any other frames, which aren't shown in detail.
+    pub other_frames: Vec<String>,
+}
+
+fn text_frames<'a>(frames: impl Iterator<Item = &'a Frame>) -> Vec<TextFrame> {
+    frames
+        .filter_map(|frame| match frame.content() {
+            Content::Text(_) => Some(TextFrame {
+                id: frame.id().to_string(),
+                values: frame.content().text_values()?.map(String::from).collect(),
+            }),
+            Content::ExtendedText(extended_text) => Some(TextFrame {
+                id: format!("{}:{}", frame.id(), extended_text.description),
+                values: vec![extended_text.value.clone()],
+            }),
+            Content::Link(link) => Some(TextFrame {
+                id: frame.id().to_string(),
+                values: vec![link.clone()],
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn offset(offset: u32) -> Option<u32> {
+    (offset != UNUSED_OFFSET).then_some(offset)
+}
+
+impl From<&Chapter> for ChapterInfo {
+    fn from(chapter: &Chapter) -> Self {
+        Self {
+            element_id: chapter.element_id.clone(),
+            title: chapter.title().map(String::from),
+            start_time: chapter.start_time,
+            end_time: chapter.end_time,
+            start: format_timestamp(chapter.start_time),
+            end: format_timestamp(chapter.end_time),
+            start_offset: offset(chapter.start_offset),
+            end_offset: offset(chapter.end_offset),
+            frames: text_frames(chapter.frames.iter()),
+        }
+    }
+}
+
+impl From<&TableOfContents> for TableOfContentsInfo {
+    fn from(toc: &TableOfContents) -> Self {
+        Self {
+            element_id: toc.element_id.clone(),
+            title: toc.title().map(String::from),
+            top_level: toc.top_level,
+            ordered: toc.ordered,
+            elements: toc.elements.clone(),
+        }
+    }
+}
+
+impl From<&Tag> for Inspection {
+    fn from(tag: &Tag) -> Self {
+        let mut chapters: Vec<_> = tag.chapters().map(ChapterInfo::from).collect();
+        chapters.sort_by_key(|chapter| chapter.start_time);
+
+        Self {
+            version: tag.version().to_string(),
+            text_frames: text_frames(tag.frames()),
+            pictures: tag
+                .pictures()
+                .map(|picture| PictureInfo {
+                    picture_type: picture.picture_type.to_string(),
+                    mime_type: picture.mime_type.clone(),
+                    description: picture.description.clone(),
+                    size: picture.data.len(),
+                })
+                .collect(),
+            comments: tag
+                .comments()
+                .map(|comment| CommentInfo {
+                    lang: comment.lang.clone(),
+                    description: comment.description.clone(),
+                    text: comment.text.clone(),
+                })
+                .collect(),
+            tables_of_contents: tag
+                .tables_of_contents()
+                .map(TableOfContentsInfo::from)
+                .collect(),
+            chapters,
+            other_frames: tag
+                .frames()
+                .filter(|frame| {
+                    !matches!(
+                        frame.content(),
+                        Content::Text(_)
+                            | Content::ExtendedText(_)
+                            | Content::Link(_)
+                            | Content::Picture(_)
+                            | Content::Comment(_)
+                            | Content::Chapter(_)
+                            | Content::TableOfContents(_)
+                    )
+                })
+                .map(|frame| frame.id().to_string())
+                .collect(),
+        }
+    }
+}
+
+fn fmt_offset(offset: Option<u32>) -> String {
+    offset.map_or_else(|| String::from("unused"), |offset| offset.to_string())
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} tag", self.version)?;
+
+        if !self.text_frames.is_empty() {
+            writeln!(f, "\nText frames:")?;
+
+            for frame in &self.text_frames {
+                writeln!(f, "  {:<6} {}", frame.id, frame.values.join("; "))?;
+            }
+        }
+
+        if !self.pictures.is_empty() {
+            writeln!(f, "\nPictures:")?;
+
+            for picture in &self.pictures {
+                writeln!(
+                    f,
+                    "  {} ({}, {} bytes) {:?}",
+                    picture.picture_type, picture.mime_type, picture.size, picture.description
+                )?;
+            }
+        }
+
+        if !self.comments.is_empty() {
+            writeln!(f, "\nComments:")?;
+
+            for comment in &self.comments {
+                writeln!(
+                    f,
+                    "  [{}] {:?}: {}",
+                    comment.lang, comment.description, comment.text
+                )?;
+            }
+        }
+
+        if !self.tables_of_contents.is_empty() {
+            writeln!(f, "\nTables of contents:")?;
+
+            for toc in &self.tables_of_contents {
+                let flags = match (toc.top_level, toc.ordered) {
+                    (true, true) => "top level, ordered",
+                    (true, false) => "top level",
+                    (false, true) => "ordered",
+                    (false, false) => "unordered",
+                };
+
+                let title = toc
+                    .title
+                    .as_ref()
+                    .map(|title| format!(" {title}"))
+                    .unwrap_or_default();
+
+                writeln!(
+                    f,
+                    "  {} ({flags}){title}: {}",
+                    toc.element_id,
+                    toc.elements.join(", ")
+                )?;
+            }
+        }
+
+        if !self.chapters.is_empty() {
+            writeln!(f, "\nChapters:")?;
+
+            for chapter in &self.chapters {
+                writeln!(
+                    f,
+                    "  {} - {}  {}  (bytes {} - {})  {}",
+                    chapter.start,
+                    chapter.end,
+                    chapter.element_id,
+                    fmt_offset(chapter.start_offset),
+                    fmt_offset(chapter.end_offset),
+                    chapter.title.as_deref().unwrap_or_default()
+                )?;
+
+                for frame in chapter.frames.iter().filter(|frame| frame.id != "TIT2") {
+                    writeln!(f, "      {:<6} {}", frame.id, frame.values.join("; "))?;
+                }
+            }
+        }
+
+        if !self.other_frames.is_empty() {
+            writeln!(f, "\nOther frames: {}", self.other_frames.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the ID3 tag of a file, to list its tags and chapters.
+pub fn inspect(path: impl AsRef<Path>) -> anyhow::Result<Inspection> {
+    let path = path.as_ref();
+    let tag = Tag::read_from_path(path)
+        .with_context(|| format!("failed to read ID3 tag from '{}'", path.display()))?;
+
+    Ok(Inspection::from(&tag))
+}
@@ -0,0 +1,135 @@
+use std::{
+    io::{self, BufRead, BufReader},
+    process::Output,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Set once Ctrl-C has been pressed.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Child processes that are still running, to kill when interrupted.
+static CHILDREN: Mutex<Vec<(u64, Arc<dyn Kill>)>> = Mutex::new(Vec::new());
+
+static NEXT_CHILD_ID: AtomicU64 = AtomicU64::new(0);
+
+trait Kill: Send + Sync {
+    fn kill(&self) -> io::Result<()>;
+}
+
+impl Kill for duct::Handle {
+    fn kill(&self) -> io::Result<()> {
+        duct::Handle::kill(self)
+    }
+}
+
+impl Kill for duct::ReaderHandle {
+    fn kill(&self) -> io::Result<()> {
+        duct::ReaderHandle::kill(self)
+    }
+}
+
+/// Handle Ctrl-C by killing any running ffmpeg or ffprobe processes and making whatever is in
+/// progress fail, so temporary files are cleaned up on the way out and the output is never left
+/// half written. Pressing Ctrl-C a second time exits right away.
+///
+/// Without this, Ctrl-C kills the process on the spot, like it would any other.
+pub fn handle_interrupts() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+
+        for (_, child) in CHILDREN.lock().unwrap().iter() {
+            let _ = child.kill();
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Whether Ctrl-C has been pressed since [`handle_interrupts`] was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail if Ctrl-C has been pressed, for long-running work that doesn't involve a child process.
+pub(crate) fn check() -> io::Result<()> {
+    if interrupted() {
+        Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+    } else {
+        Ok(())
+    }
+}
+
+/// A running child process, which is killed if Ctrl-C is pressed before it's dropped.
+struct Tracked<T> {
+    id: u64,
+    handle: Arc<T>,
+}
+
+impl<T: Kill + 'static> Tracked<T> {
+    fn start(start: impl FnOnce() -> io::Result<T>) -> io::Result<Self> {
+        // Checking while holding the lock means a process either isn't started at all, or is
+        // started in time to be killed.
+        let mut children = CHILDREN.lock().unwrap();
+        check()?;
+
+        let id = NEXT_CHILD_ID.fetch_add(1, Ordering::Relaxed);
+        let handle = Arc::new(start()?);
+        children.push((id, handle.clone()));
+
+        Ok(Self { id, handle })
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        if let Ok(mut children) = CHILDREN.lock() {
+            children.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// Run a command to completion, like [`duct::Expression::run`].
+pub(crate) fn run(expression: duct::Expression) -> io::Result<Output> {
+    let child = Tracked::start(|| expression.start())?;
+    let result = child.handle.wait().cloned();
+
+    check()?;
+    result
+}
+
+/// Run a command and return its standard output, like [`duct::Expression::read`].
+pub(crate) fn read(expression: duct::Expression) -> io::Result<String> {
+    let output = run(expression.stdout_capture())?;
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(stdout.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Run a command and pass each line of its standard output to `line`, as it's written.
+pub(crate) fn for_each_line(
+    expression: duct::Expression,
+    mut line: impl FnMut(&str),
+) -> io::Result<()> {
+    let child = Tracked::start(|| expression.reader())?;
+    let mut reader = BufReader::new(&*child.handle);
+    let mut buf = String::new();
+
+    let result = loop {
+        buf.clear();
+
+        match reader.read_line(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(_) => line(buf.trim_end()),
+            Err(err) => break Err(err),
+        }
+    };
+
+    check()?;
+    result
+}
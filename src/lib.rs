@@ -0,0 +1,531 @@
+//! Merge audio files into a single MP3 or M4B file, with chapters and optional metadata.
+//!
+//! ```no_run
+//! use merge::Merger;
+//!
+//! Merger::new("book.mp3")
+//!     .inputs(["01.mp3", "02.mp3"])
+//!     .title("My Book")
+//!     .artists(["Some Author"])
+//!     .merge()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use chrono::NaiveDate;
+use id3::{Tag, TagLike};
+
+mod append;
+mod backend;
+mod chapters;
+mod concat;
+mod cue;
+mod edit;
+mod ffmpeg;
+mod format;
+mod inputs;
+mod inspect;
+mod interrupt;
+mod metadata;
+mod mp3;
+mod mp4;
+mod output;
+mod probe;
+mod split;
+#[cfg(test)]
+mod test_util;
+mod timestamp;
+mod timing;
+mod toc;
+mod transcode;
+mod volumes;
+
+pub use append::Appender;
+pub use backend::{Backend, FakeBackend, FfmpegBackend};
+pub use chapters::{ChapterOffsets, ChapterTitle};
+pub use edit::{ChapterEdit, Editor};
+pub use format::Format;
+pub use inputs::InputOrder;
+pub use inspect::{
+    inspect, ChapterInfo, CommentInfo, Inspection, PictureInfo, TableOfContentsInfo, TextFrame,
+};
+pub use interrupt::{handle_interrupts, interrupted};
+pub use metadata::Metadata;
+pub use probe::{Packet, Probe};
+pub use split::Splitter;
+pub use timestamp::{parse_offset, parse_timestamp};
+pub use toc::TocStructure;
+pub use transcode::{Encoding, Profile, AAC_DEFAULT_BITRATE};
+
+/// Builder for a merge of several input files into a single output file.
+#[derive(Clone, Debug)]
+pub struct Merger {
+    inputs: Vec<PathBuf>,
+    input_order: InputOrder,
+    output: PathBuf,
+    metadata: Metadata,
+    chapters: bool,
+    chapter_title: ChapterTitle,
+    chapter_offsets: ChapterOffsets,
+    cue_sheet: Option<PathBuf>,
+    toc_structure: TocStructure,
+    format: Option<Format>,
+    encoding: Encoding,
+    split_volumes: bool,
+    overwrite: bool,
+    confirm_overwrite: Option<fn(&Path) -> anyhow::Result<bool>>,
+    jobs: usize,
+    backend: Arc<dyn Backend>,
+}
+
+impl Merger {
+    /// Create a new merge that writes to the given output path.
+    ///
+    /// Unless a format is set explicitly, it's determined by the output path's extension.
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        Self {
+            inputs: Vec::new(),
+            input_order: InputOrder::default(),
+            format: None,
+            output: output.into(),
+            metadata: Metadata::default(),
+            chapters: true,
+            chapter_title: ChapterTitle::default(),
+            chapter_offsets: ChapterOffsets::default(),
+            cue_sheet: None,
+            toc_structure: TocStructure::default(),
+            encoding: Encoding::default(),
+            split_volumes: false,
+            overwrite: false,
+            confirm_overwrite: None,
+            jobs: probe::default_jobs(),
+            backend: Arc::new(FfmpegBackend),
+        }
+    }
+
+    /// Add a single input file, directory, or glob pattern.
+    ///
+    /// Directories are searched recursively for audio files. The files found in a directory or
+    /// matched by a pattern are sorted according to [`Merger::input_order`], while inputs
+    /// themselves stay in the order they were added.
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.inputs.push(path.into());
+        self
+    }
+
+    /// Add several input files, directories or glob patterns, in order.
+    pub fn inputs(mut self, paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        self.inputs.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// How to order the files found in each directory or glob pattern. Defaults to natural order.
+    pub fn input_order(mut self, order: InputOrder) -> Self {
+        self.input_order = order;
+        self
+    }
+
+    /// Replace all global tag fields at once.
+    pub fn metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Set title of merged file.
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.metadata.title = Some(title.into());
+        self
+    }
+
+    /// Set subtitle of merged file.
+    pub fn subtitle(mut self, subtitle: impl Into<String>) -> Self {
+        self.metadata.subtitle = Some(subtitle.into());
+        self
+    }
+
+    /// Set artists of merged file.
+    pub fn artists(mut self, artists: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.metadata.artists = Some(artists.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set path to cover art image.
+    pub fn cover(mut self, path: impl Into<PathBuf>) -> Self {
+        self.metadata.cover = Some(path.into());
+        self
+    }
+
+    /// Set album name.
+    pub fn album(mut self, album: impl Into<String>) -> Self {
+        self.metadata.album = Some(album.into());
+        self
+    }
+
+    /// Set album artist.
+    pub fn album_artist(mut self, album_artist: impl Into<String>) -> Self {
+        self.metadata.album_artist = Some(album_artist.into());
+        self
+    }
+
+    /// Set date released.
+    pub fn date_released(mut self, date: NaiveDate) -> Self {
+        self.metadata.date_released = Some(date);
+        self
+    }
+
+    /// Set genres of merged file.
+    pub fn genres(mut self, genres: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.metadata.genres = Some(genres.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Set comments to include.
+    pub fn comments(mut self, comments: impl Into<String>) -> Self {
+        self.metadata.comments = Some(comments.into());
+        self
+    }
+
+    /// Whether to write one chapter per input file. Enabled by default.
+    pub fn chapters(mut self, enabled: bool) -> Self {
+        self.chapters = enabled;
+        self
+    }
+
+    /// Where to get the title of each per-file chapter from. Defaults to the file stem.
+    pub fn chapter_title(mut self, source: ChapterTitle) -> Self {
+        self.chapter_title = source;
+        self
+    }
+
+    /// How to fill in the byte offsets of MP3 chapters. Defaults to the positions of the frames
+    /// each chapter starts and ends at.
+    pub fn chapter_offsets(mut self, offsets: ChapterOffsets) -> Self {
+        self.chapter_offsets = offsets;
+        self
+    }
+
+    /// Read chapters from a CUE sheet instead of writing one chapter per input file.
+    ///
+    /// Each `FILE` in the sheet is matched to an input file in order, and each `TRACK` becomes a
+    /// chapter starting at its `INDEX 01`.
+    pub fn chapters_from_cue(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cue_sheet = Some(path.into());
+        self
+    }
+
+    /// How to arrange chapters in the MP3 table of contents. Defaults to a flat list.
+    pub fn toc_structure(mut self, structure: TocStructure) -> Self {
+        self.toc_structure = structure;
+        self
+    }
+
+    /// Set the output format, regardless of the output path's extension.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Encoder settings for re-encoded audio. Defaults to VBR quality 2.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Whether to split the output into numbered parts, e.g. `book (Part 1).mp3`, when it would be
+    /// too long for chapter times or too big for chapter offsets. Disabled by default, in which
+    /// case merges that are too long fail, and offsets past 4 GiB are written as "unused".
+    ///
+    /// Inputs are never split across parts, and each part gets " (Part N)" added to its title.
+    pub fn split_volumes(mut self, enabled: bool) -> Self {
+        self.split_volumes = enabled;
+        self
+    }
+
+    /// Whether to replace output files that already exist. Disabled by default, in which case the
+    /// merge fails before writing anything if any output file exists, unless
+    /// [`Merger::confirm_overwrite`] allows it.
+    ///
+    /// An output file that's also one of the inputs is never replaced.
+    pub fn overwrite(mut self, enabled: bool) -> Self {
+        self.overwrite = enabled;
+        self
+    }
+
+    /// Ask before replacing an output file that already exists, rather than failing.
+    ///
+    /// `confirm` is called with the path of each such file once it's known not to be an input, but
+    /// before anything is merged, and the merge fails unless it returns `true`.
+    pub fn confirm_overwrite(mut self, confirm: fn(&Path) -> anyhow::Result<bool>) -> Self {
+        self.confirm_overwrite = Some(confirm);
+        self
+    }
+
+    /// How many input files to probe at once. Defaults to the number of CPUs.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// The backend used to probe, concatenate and transcode files. Defaults to
+    /// [`FfmpegBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Output file path.
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// The format to write, either set explicitly or guessed from the output path.
+    pub fn output_format(&self) -> anyhow::Result<Format> {
+        match self.format {
+            Some(format) => Ok(format),
+            None => Format::from_path(&self.output).with_context(|| {
+                format!(
+                    "unsupported output file extension for '{}', expected one of: {}",
+                    self.output.display(),
+                    Format::EXTENSIONS.join(", ")
+                )
+            }),
+        }
+    }
+
+    /// Merge the inputs, write metadata, and move the result into place at the output path.
+    ///
+    /// With [`Merger::split_volumes`], the output may be split into several numbered parts
+    /// instead.
+    pub fn merge(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.inputs.is_empty(), "no input files specified");
+        // Fail on an unsupported output path before doing any work.
+        self.output_format()?;
+
+        let expanded = inputs::expand_inputs(&self.inputs).context("failed to find input files")?;
+
+        // Probing can take a while, so check the outputs first. There can't be more parts than
+        // input files.
+        let mut outputs = vec![self.output.clone()];
+        if self.split_volumes {
+            outputs.extend((1..=expanded.len()).map(|n| volumes::volume_path(&self.output, n)));
+        }
+        output::ensure_not_input(&outputs, expanded.iter().map(|(_, path)| path))?;
+
+        // Whether parts are written instead is only known once the inputs are probed.
+        if !self.split_volumes {
+            self.ensure_writable(&self.output)?;
+        }
+
+        let (inputs, probes) =
+            inputs::collect_inputs(&*self.backend, expanded, self.input_order, self.jobs)?;
+
+        let cue_sheet = match (self.chapters, &self.cue_sheet) {
+            (true, Some(path)) => Some(
+                cue::CueSheet::read_from_path(path)
+                    .and_then(|sheet| {
+                        sheet.check_inputs(path, &inputs)?;
+                        Ok(sheet)
+                    })
+                    .with_context(|| {
+                        format!(
+                            "failed to read chapters from CUE sheet '{}'",
+                            path.display()
+                        )
+                    })?,
+            ),
+            _ => None,
+        };
+
+        let format = self.output_format()?;
+        let estimates = volumes::Estimate::of(&inputs, &probes, format, self.encoding)?;
+        let tag =
+            volumes::Estimate::tag(&self.metadata, format).context("failed to set ID3 metadata")?;
+        let total = volumes::Estimate::total([&tag].into_iter().chain(&estimates));
+
+        if !self.split_volumes || total.fits() {
+            volumes::ensure_duration_fits(total)?;
+
+            if self.split_volumes {
+                self.ensure_writable(&self.output)?;
+            }
+
+            return self.merge_volume(&inputs, &probes, cue_sheet, 0, &self.output, &self.metadata);
+        }
+
+        let volumes = volumes::plan(tag, &estimates)?;
+
+        // Check every part before merging any of them, so nothing is left half done.
+        for number in 1..=volumes.len() {
+            self.ensure_writable(&volumes::volume_path(&self.output, number))?;
+        }
+
+        for (i, range) in volumes.iter().cloned().enumerate() {
+            let number = i + 1;
+            let output = volumes::volume_path(&self.output, number);
+            let mut metadata = self.metadata.clone();
+
+            if let Some(title) = &mut metadata.title {
+                *title = format!("{title} (Part {number})");
+            }
+
+            let cue_sheet = cue_sheet.as_ref().map(|sheet| cue::CueSheet {
+                files: sheet.files[range.clone()].to_vec(),
+            });
+
+            self.merge_volume(
+                &inputs[range.clone()],
+                &probes[range.clone()],
+                cue_sheet,
+                range.start,
+                &output,
+                &metadata,
+            )
+            .with_context(|| format!("failed to merge part {number} of {}", volumes.len()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure an output file may be written, asking first if it would replace an existing file.
+    fn ensure_writable(&self, output: &Path) -> anyhow::Result<()> {
+        output::ensure_writable(output, self.overwrite, self.confirm_overwrite)
+    }
+
+    /// Merge some inputs into a single output file.
+    ///
+    /// `first_index` is the index of the first input among all inputs, to keep chapter numbers
+    /// counting across volumes.
+    fn merge_volume(
+        &self,
+        inputs: &[PathBuf],
+        probes: &[probe::Probe],
+        cue_sheet: Option<cue::CueSheet>,
+        first_index: usize,
+        output: &Path,
+        metadata: &Metadata,
+    ) -> anyhow::Result<()> {
+        let format = self.output_format()?;
+
+        // Chapters, grouped by the input file they belong to.
+        let mut chapters = match (self.chapters, cue_sheet) {
+            (false, _) => Vec::new(),
+            (true, Some(sheet)) => sheet
+                .chapters(probes)
+                .context("failed to generate chapters from CUE sheet")?,
+            (true, None) => chapters::get_chapters(
+                inputs,
+                probes,
+                &self.chapter_title,
+                chapters::ChapterStart {
+                    index: first_index,
+                    time: 0,
+                },
+            )
+            .context("failed to generate chapter metadata")?
+            .into_iter()
+            .map(|chapter| vec![chapter])
+            .collect(),
+        };
+
+        let tables_of_contents = if chapters.is_empty() {
+            Vec::new()
+        } else {
+            toc::build_toc(
+                inputs,
+                &chapters,
+                self.toc_structure,
+                metadata.title.as_deref(),
+            )
+        };
+
+        // Keep the transcoded files around until the merge is done.
+        let profile = transcode::Profile::common(probes, format);
+        let (files, _transcode_dir) =
+            transcode::transcode_inputs(&*self.backend, inputs, probes, &profile, self.encoding)
+                .context("failed to transcode input files")?;
+
+        let merged_file = match format {
+            Format::Mp3 => {
+                // The tag only changes size with the chapters' times and offsets, so its space can
+                // be left in front of the audio up front and filled in once they're known.
+                let mut tag = Tag::new();
+                metadata::populate_metadata(
+                    metadata,
+                    &mut tag,
+                    chapters.iter().flatten().cloned().collect(),
+                    tables_of_contents,
+                )
+                .context("failed to set ID3 metadata")?;
+
+                let merged_file =
+                    concat::merge_files(&*self.backend, &files, output, metadata::tag_space(&tag)?)
+                        .context("failed to merge input files")?;
+
+                if !chapters.is_empty() {
+                    let durations: Vec<_> = probes
+                        .iter()
+                        .map(|probe| (probe.duration_secs * 1000.0).round() as u32)
+                        .collect();
+
+                    let stream = timing::correct_chapters(
+                        &*self.backend,
+                        &mut chapters,
+                        &durations,
+                        &files,
+                        merged_file.path(),
+                    )
+                    .context("failed to correct chapter timings")?;
+
+                    if self.chapter_offsets == ChapterOffsets::Frames {
+                        stream.set_offsets(chapters.iter_mut().flatten());
+                    }
+                }
+
+                for chapter in chapters.into_iter().flatten() {
+                    tag.add_frame(chapter);
+                }
+
+                // The space is usually what was left for the tag, unless the backend had to write
+                // the merged file itself.
+                let space = mp3::audio_start(merged_file.path())
+                    .context("failed to find start of audio in merged file")?;
+                chapters::rebase_offsets(&mut tag, 0, space)?;
+
+                metadata::write_tag(&tag, merged_file.path(), space)
+                    .context("failed to write ID3 metadata to merged file")?;
+
+                merged_file
+            }
+            Format::M4b => {
+                // Decoding concat places files by their probed durations, as the chapters assume.
+                let chapters: Vec<_> = chapters.into_iter().flatten().collect();
+
+                let total_secs = probes.iter().map(|probe| probe.duration_secs).sum();
+
+                mp4::merge_files(
+                    &*self.backend,
+                    &files,
+                    metadata,
+                    &chapters,
+                    self.encoding,
+                    total_secs,
+                    output,
+                )
+                .context("failed to merge input files")?
+            }
+        };
+
+        // Leave the output alone if the merge was interrupted.
+        interrupt::check()?;
+
+        output::persist(merged_file, output)
+            .with_context(|| format!("failed to write output file '{}'", output.display()))?;
+
+        Ok(())
+    }
+}
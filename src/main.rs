@@ -1,27 +1,50 @@
-use std::{fs, io, path::PathBuf, time::Duration};
+use std::{
+    io::{self, IsTerminal},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
-use chrono::{Datelike, NaiveDate};
-use clap::Parser;
-use id3::{
-    frame::{Chapter, Comment, Picture, PictureType},
-    Tag, TagLike, Timestamp, Version,
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
+use merge::{
+    Appender, ChapterEdit, ChapterOffsets, ChapterTitle, Editor, Encoding, Format, InputOrder,
+    Merger, Metadata, Splitter, TocStructure,
 };
-use indicatif::{ProgressBar, ProgressStyle};
-use tempfile::NamedTempFile;
-
-// We can't use a temporary path for the mergelist, unfortunately. ffmpeg considers relative paths
-// in the mergelist to be relative to the location of the mergelist, rather than the current
-// working directory.
-const MERGELIST_PATH: &str = "mergelist.txt";
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
-struct Args {
-    /// Set title of merged MP3 file
+#[clap(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    merge: MergeArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Split a chaptered MP3 file into one file per chapter
+    Split(SplitArgs),
+    /// Print the tags and chapters of an MP3 file
+    Inspect(InspectArgs),
+    /// Change the tags and chapters of an MP3 file in place, without re-merging
+    Edit(Box<EditArgs>),
+    /// Add more input files to the end of a merged MP3 file
+    Append(AppendArgs),
+}
+
+#[derive(Args, Debug)]
+struct MetadataArgs {
+    /// Set title
     #[clap(long)]
     title: Option<String>,
-    /// Set subtitle of merged MP3 file
+    /// Set subtitle
     #[clap(long)]
     subtitle: Option<String>,
     /// Semicolon-separated list of artists
@@ -29,7 +52,7 @@ struct Args {
     artists: Option<String>,
     /// Path to cover art image
     #[clap(long)]
-    cover: Option<String>,
+    cover: Option<PathBuf>,
     /// Album name
     #[clap(long)]
     album: Option<String>,
@@ -45,224 +68,356 @@ struct Args {
     /// Comments to include
     #[clap(long)]
     comments: Option<String>,
-    /// Output file path
-    output: PathBuf,
-    /// Input file paths
-    files: Vec<String>,
 }
 
-fn get_chapters(args: &Args) -> anyhow::Result<Vec<Chapter>> {
-    let mut chapters = Vec::with_capacity(args.files.len());
-    let mut current_time: u32 = 0;
-    let mut current_offset: u32 = 0;
-
-    let progress_bar = ProgressBar::new(args.files.len() as u64)
-        .with_style(ProgressStyle::default_bar().template("[{pos}/{len}] {spinner} {msg}")?);
-    progress_bar.enable_steady_tick(Duration::from_millis(100));
-
-    for (i, path) in args.files.iter().enumerate() {
-        progress_bar.inc(1);
-        progress_bar.set_message(format!("📖 generating chapter info for '{path}'..."));
-
-        let duration_secs: f64 = duct::cmd!(
-            "ffprobe",
-            "-i",
-            path,
-            "-show_entries",
-            "format=duration",
-            "-v",
-            "quiet",
-            "-of",
-            "csv=p=0"
-        )
-        .read()
-        .with_context(|| format!("failed to get duration of input file '{path}'"))?
-        .parse()
-        .with_context(|| format!("failed to parse duration of input file '{path}'"))?;
-
-        let duration_ms = (duration_secs * 1000.0).round() as u32;
-
-        let file_size = fs::metadata(path)
-            .with_context(|| format!("failed to get info for input file '{path}'"))?
-            .len() as u32;
-
-        let mut chapter = Chapter {
-            element_id: format!("chapter_{i}"),
-            start_time: current_time,
-            end_time: current_time + duration_ms,
-            start_offset: current_offset,
-            end_offset: current_offset + file_size,
-            frames: vec![],
-        };
-
-        chapter.set_title(
-            PathBuf::from(path)
-                .file_stem()
-                .with_context(|| format!("failed to get stem for input file '{path}'"))?
-                .to_string_lossy(),
-        );
+#[derive(Args, Debug)]
+struct EncodingArgs {
+    /// Constant bitrate (kbit/s) for encoded audio
+    #[clap(long, conflicts_with = "vbr-quality")]
+    bitrate: Option<u32>,
+    /// MP3 VBR quality (0-9) for encoded audio [default: 2]
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+    vbr_quality: Option<u8>,
+}
 
-        current_time += duration_ms;
-        current_offset += file_size;
+#[derive(Args, Debug)]
+struct MergeArgs {
+    #[clap(flatten)]
+    metadata: MetadataArgs,
+    /// Don't write a chapter for each input file
+    #[clap(long)]
+    no_chapters: bool,
+    /// stem (default), tag, probe, or '{n:02} - {tag.title}'
+    #[clap(long, value_parser, value_name = "SOURCE")]
+    chapter_title_source: Option<ChapterTitle>,
+    /// Chapter byte offsets: frames (default) or unused
+    #[clap(long, value_parser, value_name = "MODE")]
+    chapter_offsets: Option<ChapterOffsets>,
+    /// Table of contents layout: flat (default), dirs or files
+    #[clap(long, value_parser, value_name = "STRUCTURE")]
+    toc: Option<TocStructure>,
+    /// Read chapters from a CUE sheet instead of one per input
+    #[clap(long, value_name = "FILE", conflicts_with = "no-chapters")]
+    chapters_from_cue: Option<PathBuf>,
+    #[clap(flatten)]
+    encoding: EncodingArgs,
+    /// Split into parts if too long or big for one file
+    #[clap(long)]
+    split_volumes: bool,
+    /// Overwrite OUTPUT if it already exists
+    #[clap(long)]
+    force: bool,
+    /// Fail if OUTPUT exists instead of asking
+    #[clap(long, conflicts_with = "force")]
+    no_clobber: bool,
+    /// Output format: mp3, m4b or m4a [default: from OUTPUT]
+    #[clap(long, value_parser)]
+    format: Option<Format>,
+    /// Output file path (.mp3, .m4b or .m4a)
+    // Only optional so that parsing succeeds when a subcommand is given instead.
+    #[clap(required = true)]
+    output: Option<PathBuf>,
+    /// natural (default), track, disc-track or modified
+    #[clap(long, value_parser, value_name = "ORDER")]
+    sort: Option<InputOrder>,
+    /// Probe up to N files at once [default: number of CPUs]
+    #[clap(long, value_parser, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+    /// Input files, directories, or quoted glob patterns
+    files: Vec<PathBuf>,
+}
 
-        chapters.push(chapter);
-    }
+#[derive(Args, Debug)]
+struct SplitArgs {
+    /// Directory to write chapter files to [default: .]
+    #[clap(long, short)]
+    output_dir: Option<PathBuf>,
+    /// Re-encode to cut at exact chapter times
+    #[clap(long)]
+    reencode: bool,
+    #[clap(flatten)]
+    encoding: EncodingArgs,
+    /// Overwrite chapter files that already exist
+    #[clap(long)]
+    force: bool,
+    /// Fail if a chapter file exists instead of asking
+    #[clap(long, conflicts_with = "force")]
+    no_clobber: bool,
+    /// Chaptered MP3 file to split
+    input: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// Print as JSON
+    #[clap(long)]
+    json: bool,
+    /// MP3 file to inspect
+    input: PathBuf,
+}
 
-    progress_bar.set_message("📕 chapter info generated!");
-    progress_bar.finish();
+#[derive(Args, Debug)]
+struct EditArgs {
+    #[clap(flatten)]
+    metadata: MetadataArgs,
+    /// Rename chapter N (numbered from 1)
+    #[clap(long, value_name = "N=TITLE", value_parser = parse_numbered)]
+    rename_chapter: Vec<(usize, String)>,
+    /// Move the start of chapter N, e.g. 3=+1.5 or 3=-0:02
+    #[clap(long, value_name = "N=OFFSET", value_parser = parse_shift, allow_hyphen_values = true)]
+    shift_chapter: Vec<(usize, i64)>,
+    /// Delete chapter N, merging it into the chapter before
+    #[clap(long, value_name = "N")]
+    delete_chapter: Vec<usize>,
+    /// Add a chapter starting at TIME, e.g. 1:02:03.5=Epilogue
+    #[clap(long, value_name = "TIME=TITLE", value_parser = parse_insert)]
+    insert_chapter: Vec<(u32, String)>,
+    /// Chapter byte offsets: frames (default) or unused
+    #[clap(long, value_parser, value_name = "MODE")]
+    chapter_offsets: Option<ChapterOffsets>,
+    /// MP3 file to edit
+    input: PathBuf,
+}
 
-    Ok(chapters)
+#[derive(Args, Debug)]
+struct AppendArgs {
+    /// stem (default), tag, probe, or '{n:02} - {tag.title}'
+    #[clap(long, value_parser, value_name = "SOURCE")]
+    chapter_title_source: Option<ChapterTitle>,
+    /// Chapter byte offsets: frames (default) or unused
+    #[clap(long, value_parser, value_name = "MODE")]
+    chapter_offsets: Option<ChapterOffsets>,
+    #[clap(flatten)]
+    encoding: EncodingArgs,
+    /// natural (default), track, disc-track or modified
+    #[clap(long, value_parser, value_name = "ORDER")]
+    sort: Option<InputOrder>,
+    /// Probe up to N files at once [default: number of CPUs]
+    #[clap(long, value_parser, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+    /// Merged MP3 file to append to
+    input: PathBuf,
+    /// Input files, directories, or quoted glob patterns
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
 }
 
-fn create_mergelist(args: &Args) -> io::Result<()> {
-    let lines: Vec<_> = args
-        .files
-        .iter()
-        .map(|path| path.replace('\'', "'\\''"))
-        .map(|path| {
-            if PathBuf::from(&path).is_relative() {
-                format!("file './{path}'")
-            } else {
-                format!("file '{path}'")
-            }
-        })
-        .collect();
+fn parse_numbered(arg: &str) -> Result<(usize, String), String> {
+    let (number, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected N=VALUE, got '{arg}'"))?;
+    let number = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid chapter number '{number}'"))?;
 
-    fs::write(MERGELIST_PATH, lines.join("\n"))
+    Ok((number, value.to_string()))
 }
 
-fn merge_files() -> io::Result<NamedTempFile> {
-    let merged_file = tempfile::Builder::new()
-        .prefix("merge-output")
-        .suffix(".mp3")
-        .tempfile()?;
-
-    let progress_bar = ProgressBar::new_spinner().with_message("🔨 merging input files...");
-    progress_bar.enable_steady_tick(Duration::from_millis(100));
-
-    let _output = duct::cmd!(
-        "ffmpeg",
-        "-hide_banner",
-        "-loglevel",
-        "error",
-        "-f",
-        "concat",
-        "-safe",
-        "0",
-        "-i",
-        MERGELIST_PATH,
-        "-c",
-        "copy",
-        "-y",
-        merged_file.path()
-    )
-    .run()?;
-
-    progress_bar.finish_with_message("💽 merged!");
-
-    fs::remove_file(MERGELIST_PATH)?;
-
-    Ok(merged_file)
+fn parse_shift(arg: &str) -> Result<(usize, i64), String> {
+    let (number, offset) = parse_numbered(arg)?;
+    let offset =
+        merge::parse_offset(&offset).ok_or_else(|| format!("invalid offset '{offset}'"))?;
+
+    Ok((number, offset))
+}
+
+fn parse_insert(arg: &str) -> Result<(u32, String), String> {
+    let (time, title) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected TIME=TITLE, got '{arg}'"))?;
+    let time = merge::parse_timestamp(time).ok_or_else(|| format!("invalid time '{time}'"))?;
+
+    Ok((time, title.to_string()))
 }
 
-fn populate_metadata(
-    args: &Args,
-    metadata: &mut Tag,
-    chapters: Vec<Chapter>,
-) -> anyhow::Result<()> {
-    if let Some(title) = &args.title {
-        metadata.set_title(title);
+impl MetadataArgs {
+    fn metadata(&self) -> anyhow::Result<Metadata> {
+        let date_released = self
+            .date_released
+            .as_deref()
+            .map(|date| {
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .with_context(|| format!("failed to parse release date timestamp '{date}'"))
+            })
+            .transpose()?;
+
+        Ok(Metadata {
+            title: self.title.clone(),
+            subtitle: self.subtitle.clone(),
+            artists: split_list(&self.artists),
+            cover: self.cover.clone(),
+            album: self.album.clone(),
+            album_artist: self.album_artist.clone(),
+            date_released,
+            genres: split_list(&self.genres),
+            comments: self.comments.clone(),
+        })
     }
+}
 
-    if let Some(subtitle) = &args.subtitle {
-        metadata.set_text("TIT3", subtitle);
+impl EncodingArgs {
+    fn is_set(&self) -> bool {
+        self.bitrate.is_some() || self.vbr_quality.is_some()
     }
 
-    if let Some(artists) = &args.artists {
-        metadata.set_text_values("TPE1", artists.split(';'))
+    fn encoding(&self) -> Encoding {
+        match (self.bitrate, self.vbr_quality) {
+            (Some(kbps), _) => Encoding::Bitrate(kbps),
+            (None, Some(quality)) => Encoding::Vbr(quality),
+            (None, None) => Encoding::default(),
+        }
     }
+}
 
-    if let Some(path) = &args.cover {
-        let mime_type = mime_guess::from_path(path).first().with_context(|| {
-            format!("failed to determine a mime type for cover file '{}'", path)
-        })?;
+fn split_list(list: &Option<String>) -> Option<Vec<String>> {
+    list.as_ref()
+        .map(|list| list.split(';').map(String::from).collect())
+}
+
+fn merge(args: MergeArgs) -> anyhow::Result<()> {
+    let metadata = args.metadata.metadata()?;
+    let encoding = args.encoding.encoding();
 
-        let image_data =
-            fs::read(path).with_context(|| format!("failed to read cover file '{}'", path))?;
+    let output = args.output.context("no output file specified")?;
 
-        metadata.add_frame(Picture {
-            mime_type: mime_type.to_string(),
-            picture_type: PictureType::CoverFront,
-            description: String::new(),
-            data: image_data,
-        });
+    let mut merger = Merger::new(output)
+        .inputs(args.files)
+        .input_order(args.sort.unwrap_or_default())
+        .overwrite(args.force);
+
+    if !args.force && !args.no_clobber {
+        merger = merger.confirm_overwrite(confirm_overwrite);
     }
 
-    if let Some(album) = &args.album {
-        metadata.set_album(album);
+    if let Some(cue_sheet) = args.chapters_from_cue {
+        merger = merger.chapters_from_cue(cue_sheet);
     }
 
-    if let Some(album_artist) = &args.album_artist {
-        metadata.set_album_artist(album_artist);
+    if let Some(format) = args.format {
+        merger = merger.format(format);
     }
 
-    if let Some(date_released) = &args.date_released {
-        let parsed_date = NaiveDate::parse_from_str(date_released, "%Y-%m-%d")
-            .with_context(|| format!("failed to parse release date timestamp '{date_released}'"))?;
-
-        metadata.set_date_released(Timestamp {
-            year: parsed_date.year(),
-            month: Some(parsed_date.month() as u8),
-            day: Some(parsed_date.day() as u8),
-            hour: None,
-            minute: None,
-            second: None,
-        });
+    if let Some(jobs) = args.jobs {
+        merger = merger.jobs(jobs.get());
     }
 
-    if let Some(genres) = &args.genres {
-        metadata.set_text_values("TCON", genres.split(';'));
+    merger
+        .metadata(metadata)
+        .chapters(!args.no_chapters)
+        .chapter_title(args.chapter_title_source.unwrap_or_default())
+        .chapter_offsets(args.chapter_offsets.unwrap_or_default())
+        .toc_structure(args.toc.unwrap_or_default())
+        .encoding(encoding)
+        .split_volumes(args.split_volumes)
+        .merge()
+}
+
+/// Ask whether to overwrite an existing output file, failing if there's no one at a terminal to ask.
+fn confirm_overwrite(output: &Path) -> anyhow::Result<bool> {
+    anyhow::ensure!(
+        io::stdin().is_terminal() && io::stderr().is_terminal(),
+        "output file '{}' already exists; pass --force to overwrite it",
+        output.display()
+    );
+
+    eprint!("'{}' already exists. Overwrite? [y/N] ", output.display());
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+fn split(args: SplitArgs) -> anyhow::Result<()> {
+    let mut splitter = Splitter::new(args.input).overwrite(args.force);
+
+    if !args.force && !args.no_clobber {
+        splitter = splitter.confirm_overwrite(confirm_overwrite);
     }
 
-    if let Some(comments) = &args.comments {
-        metadata.add_frame(Comment {
-            lang: String::from("eng"),
-            description: String::new(),
-            text: comments.clone(),
-        });
+    if let Some(output_dir) = args.output_dir {
+        splitter = splitter.output_dir(output_dir);
     }
 
-    for chapter in chapters {
-        metadata.add_frame(chapter);
+    if args.reencode || args.encoding.is_set() {
+        splitter = splitter.reencode(args.encoding.encoding());
     }
 
+    splitter.split()?;
+
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let mut args: Args = Args::parse();
-    anyhow::ensure!(!args.files.is_empty(), "no input files specified");
+fn inspect(args: InspectArgs) -> anyhow::Result<()> {
+    let inspection = merge::inspect(&args.input)?;
 
-    let chapters = get_chapters(&args).context("failed to generate chapter metadata")?;
-    create_mergelist(&args).context("failed to create temporary mergelist")?;
-    let merged_file = merge_files().context("failed to merge input files")?;
-
-    let mut metadata = Tag::read_from_path(merged_file.path())
-        .context("failed to read ID3 tag from merged file")?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+    } else {
+        print!("{inspection}");
+    }
 
-    populate_metadata(&args, &mut metadata, chapters).context("failed to set ID3 metadata")?;
+    Ok(())
+}
 
-    metadata
-        .write_to_path(merged_file.path(), Version::Id3v24)
-        .context("failed to write ID3 metadata to merged file")?;
+fn edit(args: EditArgs) -> anyhow::Result<()> {
+    let mut editor = Editor::new(args.input)
+        .metadata(args.metadata.metadata()?)
+        .chapter_offsets(args.chapter_offsets.unwrap_or_default());
 
-    args.output.set_extension("mp3");
-    fs::copy(merged_file.path(), &args.output).with_context(|| {
-        format!(
-            "failed to copy merged file to output path '{}'",
-            args.output.to_string_lossy()
+    let edits = (args.rename_chapter.into_iter())
+        .map(|(number, title)| ChapterEdit::Rename(number, title))
+        .chain(
+            (args.shift_chapter.into_iter())
+                .map(|(number, offset)| ChapterEdit::Shift(number, offset)),
         )
-    })?;
+        .chain(args.delete_chapter.into_iter().map(ChapterEdit::Delete))
+        .chain(
+            (args.insert_chapter.into_iter())
+                .map(|(start, title)| ChapterEdit::Insert(start, title)),
+        );
 
-    Ok(())
+    for edit in edits {
+        editor = editor.chapter_edit(edit);
+    }
+
+    editor.edit()
+}
+
+fn append(args: AppendArgs) -> anyhow::Result<()> {
+    let mut appender = Appender::new(args.input);
+
+    if let Some(jobs) = args.jobs {
+        appender = appender.jobs(jobs.get());
+    }
+
+    appender
+        .inputs(args.files)
+        .input_order(args.sort.unwrap_or_default())
+        .chapter_title(args.chapter_title_source.unwrap_or_default())
+        .chapter_offsets(args.chapter_offsets.unwrap_or_default())
+        .encoding(args.encoding.encoding())
+        .append()
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    merge::handle_interrupts()?;
+
+    let result = match cli.command {
+        Some(Command::Split(args)) => split(args),
+        Some(Command::Inspect(args)) => inspect(args),
+        Some(Command::Edit(args)) => edit(*args),
+        Some(Command::Append(args)) => append(args),
+        None => merge(cli.merge),
+    };
+
+    // Temporary files are gone by now, and any output was either finished or never started.
+    if merge::interrupted() {
+        eprintln!("interrupted");
+        std::process::exit(130);
+    }
+
+    result
 }
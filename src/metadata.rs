@@ -0,0 +1,160 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{Datelike, NaiveDate};
+use id3::{
+    frame::{Chapter, Comment, Picture, PictureType, TableOfContents},
+    Encoder, Tag, TagLike, Timestamp, Version,
+};
+
+/// Padding left after a merged file's tag, so small changes to it can be written in place.
+const TAG_PADDING: u64 = 4096;
+
+/// Global tag fields to write to the merged file.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub artists: Option<Vec<String>>,
+    pub cover: Option<PathBuf>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub date_released: Option<NaiveDate>,
+    pub genres: Option<Vec<String>>,
+    pub comments: Option<String>,
+}
+
+pub(crate) fn populate_metadata(
+    metadata: &Metadata,
+    tag: &mut Tag,
+    chapters: Vec<Chapter>,
+    tables_of_contents: Vec<TableOfContents>,
+) -> anyhow::Result<()> {
+    if let Some(title) = &metadata.title {
+        tag.set_title(title);
+    }
+
+    if let Some(subtitle) = &metadata.subtitle {
+        tag.set_text("TIT3", subtitle);
+    }
+
+    if let Some(artists) = &metadata.artists {
+        tag.set_text_values("TPE1", artists)
+    }
+
+    if let Some(path) = &metadata.cover {
+        let mime_type = mime_guess::from_path(path).first().with_context(|| {
+            format!(
+                "failed to determine a mime type for cover file '{}'",
+                path.display()
+            )
+        })?;
+
+        let image_data = fs::read(path)
+            .with_context(|| format!("failed to read cover file '{}'", path.display()))?;
+
+        tag.add_frame(Picture {
+            mime_type: mime_type.to_string(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: image_data,
+        });
+    }
+
+    if let Some(album) = &metadata.album {
+        tag.set_album(album);
+    }
+
+    if let Some(album_artist) = &metadata.album_artist {
+        tag.set_album_artist(album_artist);
+    }
+
+    if let Some(date_released) = &metadata.date_released {
+        tag.set_date_released(Timestamp {
+            year: date_released.year(),
+            month: Some(date_released.month() as u8),
+            day: Some(date_released.day() as u8),
+            hour: None,
+            minute: None,
+            second: None,
+        });
+    }
+
+    if let Some(genres) = &metadata.genres {
+        tag.set_text_values("TCON", genres);
+    }
+
+    if let Some(comments) = &metadata.comments {
+        tag.add_frame(Comment {
+            lang: String::from("eng"),
+            description: String::new(),
+            text: comments.clone(),
+        });
+    }
+
+    for table_of_contents in tables_of_contents {
+        tag.add_frame(table_of_contents);
+    }
+
+    for chapter in chapters {
+        tag.add_frame(chapter);
+    }
+
+    Ok(())
+}
+
+fn encode_tag(tag: &Tag, padding: u64) -> anyhow::Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    (Encoder::new().version(Version::Id3v24))
+        .padding(padding as usize)
+        .encode(tag, &mut encoded)
+        .context("failed to encode ID3 tag")?;
+
+    Ok(encoded)
+}
+
+/// How much space to leave in front of merged audio for a tag the size of `tag`, with some
+/// padding on top.
+pub(crate) fn tag_space(tag: &Tag) -> anyhow::Result<u64> {
+    Ok(encode_tag(tag, 0)?.len() as u64 + TAG_PADDING)
+}
+
+/// Write an empty tag taking up `space` bytes, to be replaced by [`write_tag`] once the real tag
+/// is known.
+pub(crate) fn reserve_tag(output: &mut impl Write, space: u64) -> anyhow::Result<()> {
+    // A tag header is 10 bytes, and anything less than that is no tag at all.
+    if space >= 10 {
+        output.write_all(&encode_tag(&Tag::new(), space - 10)?)?;
+    }
+
+    Ok(())
+}
+
+/// How big `tag` will end up by [`write_tag`], in front of audio that starts at `space`.
+pub(crate) fn written_size(tag: &Tag, space: u64) -> anyhow::Result<u64> {
+    Ok((encode_tag(tag, 0)?.len() as u64).max(space))
+}
+
+/// Write `tag` over the existing tag of an MP3 file, whose audio starts at `space`.
+///
+/// If the new tag fits in the space the old one took up, along with its padding, the rest is
+/// padded and the file is written in place. Otherwise the audio has to be moved along to make
+/// room, which means rewriting the whole file.
+pub(crate) fn write_tag(tag: &Tag, path: &Path, space: u64) -> anyhow::Result<()> {
+    let len = encode_tag(tag, 0)?.len() as u64;
+
+    if len > space {
+        tag.write_to_path(path, Version::Id3v24)?;
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&encode_tag(tag, space - len)?)?;
+    file.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,851 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::interrupt;
+
+const MPEG1_BITRATES: [u32; 16] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0,
+];
+const MPEG2_BITRATES: [u32; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0,
+];
+const MPEG1_SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+/// The length of a Xing header with every field present.
+const XING_LEN: usize = 120;
+/// The length of the LAME tag that follows the Xing header.
+const LAME_TAG_LEN: usize = 36;
+
+/// How far into the audio to look for the first frame before giving up on a file being MP3.
+const MAX_SYNC_SEARCH: u64 = 64 * 1024;
+
+/// The header of a single MPEG audio Layer III frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    /// MPEG-1, as opposed to MPEG-2 or MPEG-2.5, which have half as many samples per frame.
+    pub mpeg1: bool,
+    /// In kbit/s.
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub channels: u32,
+    /// The header as it was read, for the fields not parsed above.
+    raw: [u8; 4],
+}
+
+impl FrameHeader {
+    /// Parse a frame header, ignoring anything but Layer III frames with a known bitrate.
+    pub fn parse(bytes: [u8; 4]) -> Option<Self> {
+        if bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        // Version 1 is reserved, and layer 1 means Layer III.
+        let version = (bytes[1] >> 3) & 0b11;
+        let layer = (bytes[1] >> 1) & 0b11;
+        let bitrate_index = usize::from(bytes[2] >> 4);
+        let sample_rate_index = usize::from((bytes[2] >> 2) & 0b11);
+
+        if version == 1 || layer != 1 || sample_rate_index == 3 {
+            return None;
+        }
+
+        let mpeg1 = version == 3;
+        let bitrate = if mpeg1 {
+            MPEG1_BITRATES[bitrate_index]
+        } else {
+            MPEG2_BITRATES[bitrate_index]
+        };
+
+        // Free format streams (bitrate 0) don't say how long their frames are.
+        if bitrate == 0 {
+            return None;
+        }
+
+        let sample_rate = MPEG1_SAMPLE_RATES[sample_rate_index]
+            / match version {
+                3 => 1,
+                2 => 2,
+                _ => 4,
+            };
+
+        Some(Self {
+            mpeg1,
+            bitrate,
+            sample_rate,
+            padding: bytes[2] & 0b10 != 0,
+            channels: if bytes[3] >> 6 == 0b11 { 1 } else { 2 },
+            raw: bytes,
+        })
+    }
+
+    /// A header for an informational frame in the same stream, with the lowest bitrate that
+    /// leaves room for a Xing header and LAME tag, no padding and no CRC.
+    fn for_info_frame(&self) -> Self {
+        (1..15)
+            .filter_map(|index| {
+                FrameHeader::parse([
+                    0xff,
+                    self.raw[1] | 0x01,
+                    (index << 4) | (self.raw[2] & 0x0d),
+                    self.raw[3],
+                ])
+            })
+            .find(|header| header.len() as usize >= header.xing_offset() + XING_LEN + LAME_TAG_LEN)
+            .expect("the highest bitrate always leaves room for an info frame")
+    }
+
+    /// The header of the smallest frames there can be with the given sample rate and channels.
+    fn smallest(sample_rate: u32, channels: u32) -> Option<Self> {
+        // MPEG-1, MPEG-2 and MPEG-2.5, with the sample rates halved for each.
+        let (version, index) =
+            [(3, 1), (2, 2), (0, 4)]
+                .into_iter()
+                .find_map(|(version, divisor)| {
+                    let rates = MPEG1_SAMPLE_RATES.iter();
+                    let index = rates
+                        .map(|rate| rate / divisor)
+                        .position(|r| r == sample_rate)?;
+                    Some((version, index as u8))
+                })?;
+        let mode = if channels == 1 { 0b11 << 6 } else { 0 };
+
+        Self::parse([0xff, 0xe3 | (version << 3), (1 << 4) | (index << 2), mode])
+    }
+
+    pub fn samples(&self) -> u32 {
+        if self.mpeg1 {
+            1152
+        } else {
+            576
+        }
+    }
+
+    /// The length of the whole frame in bytes, including this header.
+    pub fn len(&self) -> u32 {
+        self.samples() / 8 * self.bitrate * 1000 / self.sample_rate + u32::from(self.padding)
+    }
+
+    /// Whether another frame's header could belong to the same stream as this one.
+    pub fn matches(&self, other: &Self) -> bool {
+        self.mpeg1 == other.mpeg1 && self.sample_rate == other.sample_rate
+    }
+
+    /// Whether the header is followed by a 16-bit CRC, which is when its protection bit is 0.
+    fn has_crc(&self) -> bool {
+        self.raw[1] & 0x01 == 0
+    }
+
+    /// Where the Xing/Info header would be in a frame, right after the CRC and side information.
+    fn xing_offset(&self) -> usize {
+        let crc = if self.has_crc() { 2 } else { 0 };
+
+        4 + crc
+            + match (self.mpeg1, self.channels) {
+                (true, 1) => 17,
+                (true, _) => 32,
+                (false, 1) => 9,
+                (false, _) => 17,
+            }
+    }
+}
+
+/// Which kind of informational header a stream starts with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InfoKind {
+    /// A Xing header, written for VBR streams.
+    Xing,
+    /// The same as a Xing header, but for CBR streams.
+    Info,
+    /// Fraunhofer's VBR header.
+    Vbri,
+}
+
+/// An informational frame at the start of a stream, which holds no audio itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct InfoFrame {
+    pub kind: InfoKind,
+    /// The number of audio frames, not counting this one.
+    pub frames: Option<u32>,
+    /// The number of bytes of audio, including this frame.
+    pub bytes: Option<u32>,
+    /// Seek table, with the position at each percent of the duration as a fraction of `bytes`
+    /// out of 256.
+    pub toc: Option<[u8; 100]>,
+    pub quality: Option<u32>,
+    /// The LAME tag as it was read, for the fields not parsed here.
+    pub lame_tag: Option<[u8; LAME_TAG_LEN]>,
+    /// Samples the encoder added to the start of the stream, from the LAME tag or VBRI header.
+    pub delay: u32,
+    /// Samples the encoder added to the end of the stream, from the LAME tag.
+    pub padding: u32,
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+impl InfoFrame {
+    /// Parse the informational header in a stream's first frame, if it has one.
+    pub fn parse(header: &FrameHeader, frame: &[u8]) -> Option<Self> {
+        let xing = header.xing_offset();
+
+        // The VBRI header is always 32 bytes after the frame header, followed by its version,
+        // the encoder delay, quality, and then the sizes.
+        if frame.get(36..40) == Some(b"VBRI") {
+            return Some(Self {
+                kind: InfoKind::Vbri,
+                bytes: read_u32(frame, 46),
+                frames: read_u32(frame, 50),
+                toc: None,
+                quality: None,
+                lame_tag: None,
+                delay: read_u16(frame, 42).map_or(0, u32::from),
+                // Fraunhofer's encoder doesn't record its padding.
+                padding: 0,
+            });
+        }
+
+        let kind = match frame.get(xing..xing + 4)? {
+            b"Xing" => InfoKind::Xing,
+            b"Info" => InfoKind::Info,
+            _ => return None,
+        };
+
+        // Each field is only there if its flag is set.
+        let flags = read_u32(frame, xing + 4)?;
+        let mut at = xing + 8;
+        let mut field = |flag: u32, len: usize| {
+            let value = frame.get(at..at + len).filter(|_| flags & flag != 0);
+            at += if value.is_some() { len } else { 0 };
+            value
+        };
+
+        let frames = field(0x1, 4).and_then(|bytes| read_u32(bytes, 0));
+        let bytes = field(0x2, 4).and_then(|bytes| read_u32(bytes, 0));
+        let toc = field(0x4, 100).and_then(|toc| toc.try_into().ok());
+        let quality = field(0x8, 4).and_then(|bytes| read_u32(bytes, 0));
+
+        // The LAME tag follows the Xing header, which LAME and ffmpeg always write in full.
+        let lame = xing + XING_LEN;
+        let lame_tag: Option<[u8; LAME_TAG_LEN]> = (frame.get(lame..lame + LAME_TAG_LEN))
+            .and_then(|tag| tag.try_into().ok())
+            .filter(|tag: &[u8; LAME_TAG_LEN]| {
+                [&b"LAME"[..], b"Lavf", b"Lavc"].contains(&&tag[..4])
+            });
+        let (delay, padding) = match &lame_tag {
+            Some(tag) => {
+                let packed = u32::from_be_bytes([0, tag[21], tag[22], tag[23]]);
+                (packed >> 12, packed & 0xfff)
+            }
+            None => (0, 0),
+        };
+
+        Some(Self {
+            kind,
+            frames,
+            bytes,
+            toc,
+            quality,
+            lame_tag,
+            delay,
+            padding,
+        })
+    }
+
+    /// Describe a whole stream of frames, starting with an info frame of `info_len` bytes.
+    ///
+    /// The LAME tag, if any, is taken from `template`, with the delay from the start of the stream
+    /// and the padding from the end of it.
+    pub fn describe(
+        frames: &[Frame],
+        info_len: u32,
+        template: Option<&InfoFrame>,
+        padding: u32,
+    ) -> Self {
+        let total_bytes =
+            u64::from(info_len) + frames.iter().map(|f| u64::from(f.len)).sum::<u64>();
+
+        // Frames of a constant bitrate stream only differ in length by the padding byte.
+        let shortest = frames.iter().map(|frame| frame.len).min().unwrap_or(0);
+        let kind = if frames.iter().all(|frame| frame.len - shortest <= 1) {
+            InfoKind::Info
+        } else {
+            InfoKind::Xing
+        };
+
+        // Each entry is where the frame at that percentage of the stream starts.
+        let mut toc = [0; 100];
+        let mut pos = u64::from(info_len);
+        let mut next = 0;
+
+        for (percent, entry) in toc.iter_mut().enumerate() {
+            let target = percent * frames.len() / 100;
+            pos += (frames[next..target].iter())
+                .map(|frame| u64::from(frame.len))
+                .sum::<u64>();
+            next = target;
+
+            *entry = (pos * 256 / total_bytes.max(1)).min(255) as u8;
+        }
+
+        Self {
+            kind,
+            frames: u32::try_from(frames.len()).ok(),
+            bytes: u32::try_from(total_bytes).ok(),
+            toc: Some(toc),
+            quality: template.and_then(|info| info.quality),
+            lame_tag: template.and_then(|info| info.lame_tag),
+            delay: template.map_or(0, |info| info.delay),
+            padding,
+        }
+    }
+
+    /// Encode as a complete frame, with a header for the same stream as `header`.
+    ///
+    /// `music_crc` is the CRC of all the audio frames, for the LAME tag.
+    pub fn encode(&self, header: &FrameHeader, music_crc: u16) -> Vec<u8> {
+        let header = header.for_info_frame();
+        let mut frame = vec![0; header.len() as usize];
+        frame[..4].copy_from_slice(&header.raw);
+
+        let xing = header.xing_offset();
+        frame[xing..xing + 4].copy_from_slice(match self.kind {
+            InfoKind::Info => b"Info",
+            InfoKind::Xing | InfoKind::Vbri => b"Xing",
+        });
+        frame[xing + 4..xing + 8].copy_from_slice(&0xfu32.to_be_bytes());
+        frame[xing + 8..xing + 12].copy_from_slice(&self.frames.unwrap_or(0).to_be_bytes());
+        frame[xing + 12..xing + 16].copy_from_slice(&self.bytes.unwrap_or(0).to_be_bytes());
+        frame[xing + 16..xing + 116].copy_from_slice(&self.toc.unwrap_or([0; 100]));
+        frame[xing + 116..xing + 120].copy_from_slice(&self.quality.unwrap_or(0).to_be_bytes());
+
+        if let Some(mut tag) = self.lame_tag {
+            let packed = (self.delay.min(0xfff) << 12) | self.padding.min(0xfff);
+            tag[21..24].copy_from_slice(&packed.to_be_bytes()[1..]);
+            tag[28..32].copy_from_slice(&self.bytes.unwrap_or(0).to_be_bytes());
+            tag[32..34].copy_from_slice(&music_crc.to_be_bytes());
+
+            let lame = xing + XING_LEN;
+            frame[lame..lame + LAME_TAG_LEN].copy_from_slice(&tag);
+
+            // The tag's own CRC covers everything in the frame before it.
+            let tag_crc = crc16(0, &frame[..lame + LAME_TAG_LEN - 2]);
+            frame[lame + LAME_TAG_LEN - 2..lame + LAME_TAG_LEN]
+                .copy_from_slice(&tag_crc.to_be_bytes());
+        }
+
+        frame
+    }
+}
+
+/// The CRC-16 used by LAME tags (polynomial 0x8005, reflected), continuing from `crc`.
+fn crc16(crc: u16, bytes: &[u8]) -> u16 {
+    bytes.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte), |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// A single audio frame in a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Frame {
+    /// Byte position in the file.
+    pub pos: u64,
+    pub len: u32,
+}
+
+/// The audio frames of an MP3 file, as found by scanning it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Mp3Stream {
+    /// The header of the first audio frame.
+    pub header: FrameHeader,
+    /// The Xing/Info/VBRI frame, if the stream starts with one. It isn't among `frames`.
+    pub info: Option<InfoFrame>,
+    /// Where the informational frame is, if there is one.
+    pub info_frame: Option<Frame>,
+    pub frames: Vec<Frame>,
+    /// Where the audio starts, after any ID3v2 tag.
+    pub audio_start: u64,
+    /// Where the audio ends, before any ID3v1 or APE tag.
+    pub audio_end: u64,
+}
+
+impl Mp3Stream {
+    /// The decoded duration, leaving out the encoder delay and padding.
+    pub fn duration_secs(&self) -> f64 {
+        let samples = self.frames.len() as u64 * u64::from(self.header.samples());
+        let trimmed = self
+            .info
+            .as_ref()
+            .map_or(0, |info| u64::from(info.delay + info.padding));
+
+        samples.saturating_sub(trimmed) as f64 / f64::from(self.header.sample_rate)
+    }
+
+    /// The duration of a single frame.
+    pub fn frame_secs(&self) -> f64 {
+        f64::from(self.header.samples()) / f64::from(self.header.sample_rate)
+    }
+}
+
+/// A buffered file reader that can jump around without throwing away its buffer every time.
+struct Reader {
+    inner: BufReader<File>,
+    pos: u64,
+}
+
+impl Reader {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            inner: BufReader::new(File::open(path)?),
+            pos: 0,
+        })
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.seek_relative(pos as i64 - self.pos as i64)?;
+        self.pos = pos;
+
+        if let Err(err) = self.inner.read_exact(buf) {
+            self.pos = self.inner.stream_position()?;
+            return Err(err);
+        }
+
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn header_at(&mut self, pos: u64) -> Option<FrameHeader> {
+        let mut bytes = [0; 4];
+        self.read_at(pos, &mut bytes).ok()?;
+        FrameHeader::parse(bytes)
+    }
+
+    /// Find where the audio starts, after any ID3v2 tag and the padding following it.
+    fn id3v2_end(&mut self) -> io::Result<u64> {
+        let mut header = [0; 10];
+
+        if self.read_at(0, &mut header).is_err() || &header[..3] != b"ID3" {
+            return Ok(0);
+        }
+
+        // The tag size is a "syncsafe" integer, with 7 bits in each byte, and excludes the header
+        // and the footer, if there is one.
+        let size = (header[6..10].iter()).fold(0, |size, &b| (size << 7) | u64::from(b & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        let mut end = 10 + size + footer;
+
+        let mut byte = [0];
+        while self.read_at(end, &mut byte).is_ok() && byte[0] == 0 {
+            end += 1;
+        }
+
+        Ok(end)
+    }
+
+    /// Find where the audio ends, before any ID3v1 and APEv2 tags at the end of the file.
+    fn tags_start(&mut self, file_len: u64) -> u64 {
+        let mut end = file_len;
+
+        let mut id3v1 = [0; 3];
+        if end >= 128 && self.read_at(end - 128, &mut id3v1).is_ok() && &id3v1 == b"TAG" {
+            end -= 128;
+        }
+
+        let mut ape = [0; 32];
+        if end >= 32 && self.read_at(end - 32, &mut ape).is_ok() && &ape[..8] == b"APETAGEX" {
+            // The size includes the footer, but not the header, if there is one.
+            let size = u32::from_le_bytes([ape[12], ape[13], ape[14], ape[15]]);
+            let has_header = ape[23] & 0x80 != 0;
+            end = end.saturating_sub(u64::from(size) + if has_header { 32 } else { 0 });
+        }
+
+        end
+    }
+}
+
+/// Find where the audio starts in a file, after its ID3v2 tag and any padding following it.
+pub(crate) fn audio_start(path: &Path) -> io::Result<u64> {
+    Reader::open(path)?.id3v2_end()
+}
+
+/// Scan an MP3 file for its audio frames.
+///
+/// Returns `None` if the file doesn't start with MPEG audio Layer III frames, after any ID3v2 tag.
+pub(crate) fn scan(path: &Path) -> io::Result<Option<Mp3Stream>> {
+    let mut reader = Reader::open(path)?;
+    let file_len = reader.inner.get_ref().metadata()?.len();
+
+    let audio_start = reader.id3v2_end()?;
+    let audio_end = reader.tags_start(file_len);
+
+    // Only trust a sync word if it's followed by another frame (or the end of the audio), since
+    // the same bit pattern could easily show up in other formats.
+    let mut confirmed = |pos: u64| {
+        let header = reader.header_at(pos)?;
+        let next = pos + u64::from(header.len());
+
+        (next == audio_end || reader.header_at(next).is_some_and(|h| header.matches(&h)))
+            .then_some((pos, header))
+    };
+
+    let search_end = audio_end.min(audio_start + MAX_SYNC_SEARCH);
+    let Some((first_pos, first)) = (audio_start..search_end).find_map(&mut confirmed) else {
+        return Ok(None);
+    };
+
+    let mut first_frame = vec![0; first.len() as usize];
+    let info = match reader.read_at(first_pos, &mut first_frame) {
+        Ok(()) => InfoFrame::parse(&first, &first_frame),
+        Err(_) => None,
+    };
+    let info_frame = info.as_ref().map(|_| Frame {
+        pos: first_pos,
+        len: first.len(),
+    });
+
+    let mut frames = Vec::new();
+    let mut pos = match &info_frame {
+        Some(frame) => frame.pos + u64::from(frame.len),
+        None => first_pos,
+    };
+    let mut header = None;
+
+    while pos + 4 <= audio_end {
+        let Some(next) = reader.header_at(pos).filter(|h| first.matches(h)) else {
+            // Skip over junk between frames, one byte at a time.
+            pos += 1;
+            continue;
+        };
+
+        // A frame cut off by the end of the file still holds some audio.
+        let len = next.len().min((audio_end - pos) as u32);
+        frames.push(Frame { pos, len });
+        header.get_or_insert(next);
+        pos += u64::from(len);
+    }
+
+    Ok(Some(Mp3Stream {
+        header: header.unwrap_or(first),
+        info,
+        info_frame,
+        frames,
+        audio_start,
+        audio_end,
+    }))
+}
+
+/// Concatenate the audio frames of several MP3 files, leaving out their tags and informational
+/// frames, and start the result with a new info frame describing the whole stream.
+///
+/// The streams must have been scanned from `inputs`, and all match the first one.
+pub(crate) fn concatenate(
+    inputs: &[impl AsRef<Path>],
+    streams: &[Mp3Stream],
+    output: &mut (impl Write + Seek),
+) -> io::Result<()> {
+    let (Some(first), Some(last)) = (streams.first(), streams.last()) else {
+        return Ok(());
+    };
+
+    // Leave room for the info frame, which can only be filled in once every frame is written.
+    let start = output.stream_position()?;
+    let info_len = first.header.for_info_frame().len();
+    output.write_all(&vec![0; info_len as usize])?;
+
+    let mut frames = Vec::new();
+    let mut music_crc = 0;
+    let mut buf = Vec::new();
+
+    for (path, stream) in inputs.iter().zip(streams) {
+        interrupt::check()?;
+        let mut reader = Reader::open(path.as_ref())?;
+
+        for frame in &stream.frames {
+            buf.resize(frame.len as usize, 0);
+            reader.read_at(frame.pos, &mut buf)?;
+
+            // A frame cut off by the end of its file would swallow the start of the next one, so
+            // it's padded back to the length its header gives.
+            let len = FrameHeader::parse([buf[0], buf[1], buf[2], buf[3]])
+                .map_or(frame.len, |header| header.len().max(frame.len));
+            buf.resize(len as usize, 0);
+            output.write_all(&buf)?;
+
+            music_crc = crc16(music_crc, &buf);
+            frames.push(Frame {
+                pos: frame.pos,
+                len,
+            });
+        }
+    }
+
+    let padding = last.info.as_ref().map_or(0, |info| info.padding);
+    let info = InfoFrame::describe(&frames, info_len, first.info.as_ref(), padding);
+
+    output.seek(SeekFrom::Start(start))?;
+    output.write_all(&info.encode(&first.header, music_crc))?;
+    output.seek(SeekFrom::End(0))?;
+
+    Ok(())
+}
+
+/// Write silent frames lasting at least `duration_secs`.
+pub(crate) fn write_silence(
+    output: &mut impl Write,
+    sample_rate: u32,
+    channels: u32,
+    duration_secs: f64,
+) -> io::Result<()> {
+    let header = FrameHeader::smallest(sample_rate, channels).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("MP3 can't have a sample rate of {sample_rate} Hz"),
+        )
+    })?;
+
+    // Side information that's all zeroes means there's no audio data, which decodes to silence.
+    let mut frame = vec![0; header.len() as usize];
+    frame[..4].copy_from_slice(&header.raw);
+
+    let samples = duration_secs.max(0.0) * f64::from(sample_rate);
+    for _ in 0..(samples / f64::from(header.samples())).ceil() as u64 {
+        output.write_all(&frame)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_util::file;
+
+    /// 128 kbit/s, 44.1 kHz joint stereo, without a CRC.
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x64];
+
+    /// The same, but with a CRC after the header.
+    const HEADER_WITH_CRC: [u8; 4] = [0xff, 0xfa, 0x90, 0x64];
+
+    fn frame(header: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0; FrameHeader::parse(header).unwrap().len() as usize];
+        frame[..4].copy_from_slice(&header);
+        frame
+    }
+
+    fn frames(count: usize) -> Vec<u8> {
+        frame(HEADER).repeat(count)
+    }
+
+    /// A frame with a Xing or Info header holding every field, and a LAME tag.
+    fn xing_frame(header: [u8; 4], kind: &[u8; 4], delay: u32, padding: u32) -> Vec<u8> {
+        let mut frame = frame(header);
+        let xing = FrameHeader::parse(header).unwrap().xing_offset();
+
+        frame[xing..xing + 4].copy_from_slice(kind);
+        frame[xing + 4..xing + 8].copy_from_slice(&0xfu32.to_be_bytes());
+        frame[xing + 8..xing + 12].copy_from_slice(&1234u32.to_be_bytes());
+        frame[xing + 12..xing + 16].copy_from_slice(&567_890u32.to_be_bytes());
+        for (i, entry) in frame[xing + 16..xing + 116].iter_mut().enumerate() {
+            *entry = (i * 2) as u8;
+        }
+        frame[xing + 116..xing + 120].copy_from_slice(&57u32.to_be_bytes());
+
+        let lame = xing + XING_LEN;
+        frame[lame..lame + 9].copy_from_slice(b"LAME3.100");
+        let packed = (delay << 12) | padding;
+        frame[lame + 21..lame + 24].copy_from_slice(&packed.to_be_bytes()[1..]);
+
+        frame
+    }
+
+    fn scan_bytes(bytes: &[u8]) -> Mp3Stream {
+        scan(file(bytes).path()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn concatenate_pads_truncated_frames() {
+        let mut cut = frames(10);
+        cut.truncate(cut.len() - 10);
+        let inputs = [file(&cut), file(&frames(5))];
+        let streams: Vec<_> = (inputs.iter())
+            .map(|input| scan(input.path()).unwrap().unwrap())
+            .collect();
+        assert_eq!(streams[0].frames.len(), 10);
+
+        let mut output = Cursor::new(Vec::new());
+        concatenate(
+            &inputs.each_ref().map(|input| input.path()),
+            &streams,
+            &mut output,
+        )
+        .unwrap();
+
+        let merged = scan_bytes(output.get_ref());
+        assert_eq!(merged.frames.len(), 15);
+        assert_eq!(merged.info.unwrap().frames, Some(15));
+    }
+
+    #[test]
+    fn parses_xing_header_and_lame_tag() {
+        let frame = xing_frame(HEADER, b"Xing", 576, 1105);
+        let info = InfoFrame::parse(&FrameHeader::parse(HEADER).unwrap(), &frame).unwrap();
+
+        assert_eq!(info.kind, InfoKind::Xing);
+        assert_eq!(info.frames, Some(1234));
+        assert_eq!(info.bytes, Some(567_890));
+        assert_eq!(info.toc.unwrap()[..3], [0, 2, 4]);
+        assert_eq!(info.quality, Some(57));
+        assert_eq!(&info.lame_tag.unwrap()[..4], b"LAME");
+        assert_eq!((info.delay, info.padding), (576, 1105));
+    }
+
+    #[test]
+    fn parses_info_header_with_some_fields() {
+        let header = FrameHeader::parse(HEADER).unwrap();
+        let xing = header.xing_offset();
+        let mut frame = frame(HEADER);
+        frame[xing..xing + 4].copy_from_slice(b"Info");
+        frame[xing + 4..xing + 8].copy_from_slice(&0x9u32.to_be_bytes());
+        frame[xing + 8..xing + 12].copy_from_slice(&42u32.to_be_bytes());
+        frame[xing + 12..xing + 16].copy_from_slice(&100u32.to_be_bytes());
+
+        let info = InfoFrame::parse(&header, &frame).unwrap();
+
+        assert_eq!(info.kind, InfoKind::Info);
+        assert_eq!(info.frames, Some(42));
+        assert_eq!(info.bytes, None);
+        assert_eq!(info.toc, None);
+        assert_eq!(info.quality, Some(100));
+        assert_eq!(info.lame_tag, None);
+        assert_eq!((info.delay, info.padding), (0, 0));
+    }
+
+    #[test]
+    fn parses_vbri_header() {
+        let mut frame = frame(HEADER);
+        frame[36..40].copy_from_slice(b"VBRI");
+        frame[40..42].copy_from_slice(&1u16.to_be_bytes());
+        frame[42..44].copy_from_slice(&576u16.to_be_bytes());
+        frame[46..50].copy_from_slice(&567_890u32.to_be_bytes());
+        frame[50..54].copy_from_slice(&1234u32.to_be_bytes());
+
+        let info = InfoFrame::parse(&FrameHeader::parse(HEADER).unwrap(), &frame).unwrap();
+
+        assert_eq!(info.kind, InfoKind::Vbri);
+        assert_eq!(info.frames, Some(1234));
+        assert_eq!(info.bytes, Some(567_890));
+        assert_eq!((info.delay, info.padding), (576, 0));
+    }
+
+    #[test]
+    fn ignores_audio_frames() {
+        let header = FrameHeader::parse(HEADER).unwrap();
+        assert_eq!(InfoFrame::parse(&header, &frame(HEADER)), None);
+    }
+
+    #[test]
+    fn finds_xing_header_after_crc() {
+        // The CRC takes the two bytes after the header, before the side information.
+        let mut bytes = frame(HEADER_WITH_CRC);
+        bytes[38..42].copy_from_slice(b"Xing");
+        bytes.extend(frame(HEADER_WITH_CRC).repeat(3));
+        let stream = scan_bytes(&bytes);
+
+        assert_eq!(stream.info.unwrap().kind, InfoKind::Xing);
+        assert_eq!(stream.frames.len(), 3);
+    }
+
+    #[test]
+    fn duration_leaves_out_delay_and_padding() {
+        let mut bytes = xing_frame(HEADER, b"Info", 576, 1152 + 576);
+        bytes.extend(frames(10));
+        let stream = scan_bytes(&bytes);
+
+        assert_eq!(stream.frames.len(), 10);
+        assert_eq!(stream.duration_secs(), (8 * 1152) as f64 / 44_100.0);
+    }
+
+    #[test]
+    fn skips_tags_around_audio() {
+        let mut id3v2 = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        id3v2.extend([b'T'; 20]);
+
+        let mut ape = vec![b'A'; 16];
+        ape.extend(b"APETAGEX");
+        ape.extend(2000u32.to_le_bytes());
+        ape.extend((16u32 + 32).to_le_bytes());
+        ape.extend([0; 16]);
+
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0xff);
+
+        let audio = frames(5);
+        let bytes = [&id3v2[..], &audio, &ape, &id3v1].concat();
+        let stream = scan_bytes(&bytes);
+
+        assert_eq!(stream.audio_start, 30);
+        assert_eq!(stream.audio_end, 30 + audio.len() as u64);
+        assert_eq!(stream.frames.len(), 5);
+        assert!(stream.frames.iter().all(|frame| frame.len == 417));
+    }
+
+    #[test]
+    fn skips_junk_between_frames() {
+        let bytes = [frames(3), b"junk".to_vec(), frames(2)].concat();
+        let stream = scan_bytes(&bytes);
+
+        let positions: Vec<_> = stream.frames.iter().map(|frame| frame.pos).collect();
+        assert_eq!(positions, [0, 417, 834, 1255, 1672]);
+    }
+
+    #[test]
+    fn encoded_info_frame_describes_stream() {
+        let template = InfoFrame::parse(
+            &FrameHeader::parse(HEADER).unwrap(),
+            &xing_frame(HEADER, b"Xing", 576, 0),
+        );
+        let mut output = Cursor::new(Vec::new());
+        let input = file(&frames(20));
+        let stream = Mp3Stream {
+            info: template,
+            ..scan(input.path()).unwrap().unwrap()
+        };
+        concatenate(&[input.path()], &[stream], &mut output).unwrap();
+
+        let merged = scan_bytes(output.get_ref());
+        let info = merged.info.unwrap();
+
+        assert_eq!(info.kind, InfoKind::Info);
+        assert_eq!(info.frames, Some(20));
+        assert_eq!(info.bytes, Some(output.get_ref().len() as u32));
+        assert_eq!(info.delay, 576);
+
+        // The LAME tag's CRC covers the frame up to it.
+        let info_frame = merged.info_frame.unwrap();
+        let lame_end = FrameHeader::parse(HEADER)
+            .unwrap()
+            .for_info_frame()
+            .xing_offset()
+            + XING_LEN
+            + LAME_TAG_LEN;
+        let frame = &output.get_ref()[..info_frame.len as usize];
+        let crc = u16::from_be_bytes([frame[lame_end - 2], frame[lame_end - 1]]);
+        assert_eq!(crc16(0, &frame[..lame_end - 2]), crc);
+    }
+}
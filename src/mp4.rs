@@ -0,0 +1,166 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use anyhow::Context;
+use id3::{frame::Chapter, TagLike};
+use tempfile::NamedTempFile;
+
+use crate::{backend::Backend, concat, ffmpeg, metadata::Metadata, output, transcode::Encoding};
+
+// https://ffmpeg.org/ffmpeg-formats.html#Metadata-1
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Build an FFMETADATA file with the global tags and chapters for the MP4 muxer.
+///
+/// ffmpeg maps these keys to the matching iTunes atoms (`©nam`, `©ART`, `©alb`, ...), and writes
+/// the chapters both as a Nero `chpl` atom and as a QuickTime chapter text track.
+pub(crate) fn create_ffmetadata(metadata: &Metadata, chapters: &[Chapter]) -> String {
+    let mut lines = vec![String::from(";FFMETADATA1")];
+    let mut push = |key: &str, value: &str| lines.push(format!("{key}={}", escape(value)));
+
+    if let Some(title) = &metadata.title {
+        push("title", title);
+    }
+
+    if let Some(subtitle) = &metadata.subtitle {
+        push("description", subtitle);
+    }
+
+    if let Some(artists) = &metadata.artists {
+        push("artist", &artists.join(";"));
+    }
+
+    if let Some(album) = &metadata.album {
+        push("album", album);
+    }
+
+    if let Some(album_artist) = &metadata.album_artist {
+        push("album_artist", album_artist);
+    }
+
+    if let Some(date_released) = &metadata.date_released {
+        push("date", &date_released.format("%Y-%m-%d").to_string());
+    }
+
+    if let Some(genres) = &metadata.genres {
+        push("genre", &genres.join(";"));
+    }
+
+    if let Some(comments) = &metadata.comments {
+        push("comment", comments);
+    }
+
+    for chapter in chapters {
+        lines.push(String::from("[CHAPTER]"));
+        lines.push(String::from("TIMEBASE=1/1000"));
+        lines.push(format!("START={}", chapter.start_time));
+        lines.push(format!("END={}", chapter.end_time));
+
+        if let Some(title) = chapter.title() {
+            lines.push(format!("title={}", escape(title)));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Encode the inputs into a single file next to `output`, with chapters and metadata.
+///
+/// `total_secs` is how long the inputs are altogether, to show how far the encoding has got.
+pub(crate) fn merge_files(
+    backend: &dyn Backend,
+    inputs: &[impl AsRef<Path>],
+    metadata: &Metadata,
+    chapters: &[Chapter],
+    encoding: Encoding,
+    total_secs: f64,
+    output: &Path,
+) -> anyhow::Result<NamedTempFile> {
+    let merged_file = output::temp_file_for(output, ".m4b")?;
+
+    let ffmetadata_file = tempfile::Builder::new()
+        .prefix("merge-ffmetadata")
+        .suffix(".txt")
+        .tempfile()?;
+    fs::write(
+        ffmetadata_file.path(),
+        create_ffmetadata(metadata, chapters),
+    )
+    .context("failed to write chapter and tag metadata")?;
+
+    let message = "🔨 encoding input files...";
+    let progress_bar = ffmpeg::progress_bar(total_secs, message)?;
+    let inputs: Vec<_> = inputs.iter().map(AsRef::as_ref).collect();
+
+    backend.encode_m4b(
+        &inputs,
+        ffmetadata_file.path(),
+        metadata.cover.as_deref(),
+        encoding,
+        merged_file.path(),
+        &mut |secs| ffmpeg::set_progress(&progress_bar, message, secs),
+    )?;
+
+    progress_bar.finish_with_message("💽 merged!");
+
+    Ok(merged_file)
+}
+
+pub(crate) fn ffmpeg_encode(
+    inputs: &[&Path],
+    ffmetadata: &Path,
+    cover: Option<&Path>,
+    encoding: Encoding,
+    output: &Path,
+    progress: &mut dyn FnMut(f64),
+) -> anyhow::Result<()> {
+    let mergelist = concat::create_mergelist(inputs)?;
+
+    let mut args: Vec<OsString> = vec![
+        "-hide_banner".into(),
+        "-loglevel".into(),
+        "error".into(),
+        "-f".into(),
+        "concat".into(),
+        "-safe".into(),
+        "0".into(),
+        "-i".into(),
+        mergelist.path().into(),
+        "-f".into(),
+        "ffmetadata".into(),
+        "-i".into(),
+        ffmetadata.into(),
+    ];
+
+    if let Some(cover) = cover {
+        args.extend(["-i".into(), cover.into()]);
+    }
+
+    args.extend(["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1"].map(Into::into));
+
+    // Cover art goes in the `covr` atom, which ffmpeg writes for attached picture streams.
+    if cover.is_some() {
+        args.extend(["-map", "2:v", "-c:v", "copy"].map(Into::into));
+        args.extend(["-disposition:v:0", "attached_pic"].map(Into::into));
+    }
+
+    args.extend(["-c:a", "aac"].map(Into::into));
+    args.extend(encoding.aac_args().map(Into::into));
+    // The temporary file already exists, so ffmpeg has to be told to replace it. Whether the real
+    // output may be replaced has been checked before merging started.
+    args.extend(["-movflags", "+faststart", "-y"].map(Into::into));
+    args.push(output.into());
+
+    ffmpeg::run(args, progress)
+}
@@ -0,0 +1,102 @@
+use std::{fs, io, path::Path};
+
+use anyhow::Context;
+use tempfile::NamedTempFile;
+
+/// Make sure none of the `outputs` is also one of the `inputs`, which would be replaced with the
+/// merged file.
+pub(crate) fn ensure_not_input(
+    outputs: &[impl AsRef<Path>],
+    inputs: impl IntoIterator<Item = impl AsRef<Path>>,
+) -> anyhow::Result<()> {
+    // Outputs that don't exist yet can't be inputs, so usually there's nothing to compare.
+    let existing: Vec<_> = (outputs.iter().map(AsRef::as_ref))
+        .filter_map(|output| Some((fs::canonicalize(output).ok()?, output)))
+        .collect();
+    if existing.is_empty() {
+        return Ok(());
+    }
+
+    for input in inputs {
+        let Ok(input) = fs::canonicalize(input) else {
+            continue;
+        };
+
+        if let Some((_, output)) = existing.iter().find(|(path, _)| *path == input) {
+            anyhow::bail!("output file '{}' is also an input file", output.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Make sure `output` may be written, asking `confirm` first if it would replace an existing file.
+pub(crate) fn ensure_writable(
+    output: &Path,
+    overwrite: bool,
+    confirm: Option<fn(&Path) -> anyhow::Result<bool>>,
+) -> anyhow::Result<()> {
+    if overwrite || !output.exists() {
+        return Ok(());
+    }
+
+    let display = output.display();
+    let Some(confirm) = confirm else {
+        anyhow::bail!("output file '{display}' already exists");
+    };
+    anyhow::ensure!(confirm(output)?, "not overwriting output file '{display}'");
+
+    Ok(())
+}
+
+/// Create a temporary file in the same directory as `output`, so it can be moved into place in one
+/// step once it's complete.
+pub(crate) fn temp_file_for(output: &Path, suffix: &str) -> io::Result<NamedTempFile> {
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".merge-output").suffix(suffix);
+
+    // Temporary files are only readable by their owner, but the output should get the same
+    // permissions as any other new file.
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+
+    builder.tempfile_in(dir)
+}
+
+/// Move a complete temporary file to `output`, replacing any file that's already there at once.
+///
+/// A replaced file's permissions are kept. If the temporary file is on another filesystem, it's
+/// copied next to the output first, so the output is still never left half written.
+pub(crate) fn persist(file: NamedTempFile, output: &Path) -> anyhow::Result<()> {
+    let display = output.display();
+
+    if let Ok(existing) = fs::metadata(output) {
+        fs::set_permissions(file.path(), existing.permissions())
+            .with_context(|| format!("failed to copy permissions of '{display}'"))?;
+    }
+
+    let file = match file.persist(output) {
+        Ok(_) => return Ok(()),
+        Err(err) => err.file,
+    };
+
+    let suffix = output
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let copy = temp_file_for(output, &suffix)
+        .with_context(|| format!("failed to create temporary file next to '{display}'"))?;
+
+    // Copying takes the permissions along too.
+    fs::copy(file.path(), copy.path())
+        .with_context(|| format!("failed to copy merged file next to '{display}'"))?;
+    copy.persist(output)
+        .with_context(|| format!("failed to move merged file to '{display}'"))?;
+
+    Ok(())
+}
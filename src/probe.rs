@@ -0,0 +1,325 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use id3::{Content, Tag};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{backend::Backend, interrupt, mp3};
+
+/// The names ffprobe gives to ID3 frames, so tags look the same however a file was probed. Other
+/// frames keep their ID, and user-defined `TXXX` frames their description.
+const ID3_TAG_NAMES: &[(&str, &str)] = &[
+    ("TALB", "album"),
+    ("TCOM", "composer"),
+    ("TCON", "genre"),
+    ("TCOP", "copyright"),
+    ("TDRC", "date"),
+    ("TENC", "encoded_by"),
+    ("TIT1", "grouping"),
+    ("TIT2", "title"),
+    ("TLAN", "language"),
+    ("TPE1", "artist"),
+    ("TPE2", "album_artist"),
+    ("TPE3", "performer"),
+    ("TPOS", "disc"),
+    ("TPUB", "publisher"),
+    ("TRCK", "track"),
+    ("TSSE", "encoder"),
+    ("TYER", "date"),
+];
+
+/// Duration, audio stream parameters and tags of an input file, as reported by ffprobe or found by
+/// scanning an MP3 file's frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Probe {
+    pub duration_secs: f64,
+    pub codec_name: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Container and audio stream tags, keyed by lowercase tag name.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Probe {
+    /// A probe of an untagged audio stream, e.g. for a [`FakeBackend`](crate::FakeBackend) to
+    /// report.
+    pub fn new(
+        codec_name: impl Into<String>,
+        sample_rate: u32,
+        channels: u32,
+        duration_secs: f64,
+    ) -> Self {
+        Self {
+            duration_secs,
+            codec_name: codec_name.into(),
+            sample_rate,
+            channels,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+/// Probe a file, scanning it directly if it's an MP3 file, or with the backend otherwise.
+pub(crate) fn probe(backend: &dyn Backend, path: &Path) -> anyhow::Result<Probe> {
+    interrupt::check()?;
+
+    match probe_mp3(path)? {
+        Some(probe) => Ok(probe),
+        None => backend.probe(path),
+    }
+}
+
+fn scan_mp3(path: &Path) -> anyhow::Result<Option<mp3::Mp3Stream>> {
+    mp3::scan(path).with_context(|| format!("failed to scan input file '{}'", path.display()))
+}
+
+fn probe_mp3(path: &Path) -> anyhow::Result<Option<Probe>> {
+    let Some(stream) = scan_mp3(path)? else {
+        return Ok(None);
+    };
+
+    // Tags are a nice-to-have, so a broken tag shouldn't stop the merge.
+    let tag = Tag::read_from_path(path).ok();
+    let tags = (tag.iter().flat_map(Tag::frames))
+        .filter_map(|frame| match frame.content() {
+            Content::ExtendedText(text) => Some((text.description.clone(), text.value.clone())),
+            Content::Comment(comment) if comment.description.is_empty() => {
+                Some((String::from("comment"), comment.text.clone()))
+            }
+            content => {
+                let name = (ID3_TAG_NAMES.iter())
+                    .find(|(id, _)| *id == frame.id())
+                    .map_or(frame.id(), |(_, name)| name);
+                Some((name.to_string(), content.text()?.replace('\0', "/")))
+            }
+        })
+        .map(|(key, value)| (key.to_lowercase(), value))
+        .collect();
+
+    Ok(Some(Probe {
+        duration_secs: stream.duration_secs(),
+        codec_name: String::from("mp3"),
+        sample_rate: stream.header.sample_rate,
+        channels: stream.header.channels,
+        tags,
+    }))
+}
+
+pub(crate) fn ffprobe(path: &Path) -> anyhow::Result<Probe> {
+    let display = path.display();
+
+    let output = interrupt::read(duct::cmd!(
+        "ffprobe",
+        "-i",
+        path,
+        "-select_streams",
+        "a:0",
+        "-show_entries",
+        "format=duration:format_tags:stream=codec_name,sample_rate,channels:stream_tags",
+        "-v",
+        "quiet",
+        "-of",
+        "default=noprint_wrappers=1"
+    ))
+    .with_context(|| format!("failed to probe input file '{display}'"))?;
+
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .with_context(|| format!("no {name} reported for input file '{display}'"))
+    };
+
+    // ffprobe prints stream tags (e.g. Vorbis comments) before container tags, so container tags
+    // win when both are present.
+    let tags = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("TAG:")?.split_once('='))
+        .map(|(key, value)| (key.to_lowercase(), value.to_string()))
+        .collect();
+
+    Ok(Probe {
+        duration_secs: field("duration")?
+            .parse()
+            .with_context(|| format!("failed to parse duration of input file '{display}'"))?,
+        codec_name: field("codec_name")?.to_string(),
+        sample_rate: field("sample_rate")?
+            .parse()
+            .with_context(|| format!("failed to parse sample rate of input file '{display}'"))?,
+        channels: field("channels")?
+            .parse()
+            .with_context(|| format!("failed to parse channels of input file '{display}'"))?,
+        tags,
+    })
+}
+
+/// How many files to probe at once by default: one per CPU.
+pub(crate) fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Probe every input, up to `jobs` at a time, returning the probes in the same order as the inputs.
+pub(crate) fn probe_inputs(
+    backend: &dyn Backend,
+    inputs: &[impl AsRef<Path> + Sync],
+    jobs: usize,
+) -> anyhow::Result<Vec<Probe>> {
+    let progress_bar = ProgressBar::new(inputs.len() as u64)
+        .with_style(ProgressStyle::default_bar().template("[{pos}/{len}] {spinner} {msg}")?);
+    progress_bar.enable_steady_tick(Duration::from_millis(100));
+
+    // Each worker takes the next input nobody has started on, until there are none left or one of
+    // them failed.
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    let mut probed: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, inputs.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut probed = Vec::new();
+
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(path) = inputs.get(i).map(AsRef::as_ref) else {
+                            break;
+                        };
+
+                        let display = path.display();
+                        progress_bar.set_message(format!("📖 probing input file '{display}'..."));
+
+                        let result = probe(backend, path);
+                        failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                        progress_bar.inc(1);
+                        probed.push((i, result));
+                    }
+
+                    probed
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("probing thread panicked"))
+            .collect()
+    });
+
+    // Inputs are started in order, so everything before a failure has been probed, and the first
+    // failure in input order is reported no matter which worker got there first.
+    probed.sort_by_key(|(i, _)| *i);
+    let probes = (probed.into_iter())
+        .map(|(_, result)| result)
+        .collect::<anyhow::Result<_>>()?;
+
+    progress_bar.set_message("📕 input files probed!");
+    progress_bar.finish();
+
+    Ok(probes)
+}
+
+/// Count the packets (for MP3, frames) in the audio stream of a file, without decoding it.
+pub(crate) fn count_packets(backend: &dyn Backend, path: &Path) -> anyhow::Result<usize> {
+    match scan_mp3(path)? {
+        Some(stream) => Ok(stream.frames.len()),
+        None => backend.count_packets(path),
+    }
+}
+
+pub(crate) fn ffprobe_count_packets(path: &Path) -> anyhow::Result<usize> {
+    let display = path.display();
+
+    let output = interrupt::read(duct::cmd!(
+        "ffprobe",
+        "-i",
+        path,
+        "-select_streams",
+        "a:0",
+        "-count_packets",
+        "-show_entries",
+        "stream=nb_read_packets",
+        "-v",
+        "quiet",
+        "-of",
+        "csv=p=0"
+    ))
+    .with_context(|| format!("failed to count packets in '{display}'"))?;
+
+    output
+        .trim()
+        .parse()
+        .with_context(|| format!("failed to parse packet count of '{display}'"))
+}
+
+/// Timing and position of a single packet in an audio stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Packet {
+    /// Presentation time, in seconds.
+    pub time: f64,
+    /// Duration, in seconds.
+    pub duration: f64,
+    /// Size, in bytes.
+    pub size: u64,
+    /// Byte position in the file.
+    pub pos: u64,
+}
+
+/// List every packet in the audio stream of a file, in order.
+pub(crate) fn packets(backend: &dyn Backend, path: &Path) -> anyhow::Result<Vec<Packet>> {
+    let Some(stream) = scan_mp3(path)? else {
+        return backend.packets(path);
+    };
+    let duration = stream.frame_secs();
+
+    Ok((stream.frames.iter().enumerate())
+        .map(|(i, frame)| Packet {
+            time: i as f64 * duration,
+            duration,
+            size: u64::from(frame.len),
+            pos: frame.pos,
+        })
+        .collect())
+}
+
+pub(crate) fn ffprobe_packets(path: &Path) -> anyhow::Result<Vec<Packet>> {
+    let display = path.display();
+
+    // ffprobe prints fields in its own order, regardless of the order they're asked for in.
+    let output = interrupt::read(duct::cmd!(
+        "ffprobe",
+        "-i",
+        path,
+        "-select_streams",
+        "a:0",
+        "-show_entries",
+        "packet=pts_time,duration_time,size,pos",
+        "-v",
+        "quiet",
+        "-of",
+        "csv=p=0"
+    ))
+    .with_context(|| format!("failed to list packets in '{display}'"))?;
+
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.trim().split(',');
+
+            Some(Packet {
+                time: fields.next()?.parse().ok()?,
+                duration: fields.next()?.parse().ok()?,
+                size: fields.next()?.parse().ok()?,
+                pos: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect::<Option<_>>()
+        .with_context(|| format!("failed to parse packets of '{display}'"))
+}
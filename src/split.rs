@@ -0,0 +1,256 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use id3::{frame::Chapter, Content, Tag, TagLike, Version};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::{
+    backend::{Backend, FfmpegBackend},
+    interrupt, output,
+    transcode::Encoding,
+};
+
+/// Builder for splitting a chaptered MP3 file into one file per chapter.
+#[derive(Clone, Debug)]
+pub struct Splitter {
+    input: PathBuf,
+    output_dir: PathBuf,
+    encoding: Option<Encoding>,
+    overwrite: bool,
+    confirm_overwrite: Option<fn(&Path) -> anyhow::Result<bool>>,
+    backend: Arc<dyn Backend>,
+}
+
+/// Replace characters that aren't allowed in file names on common platforms.
+fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    sanitized.trim().trim_end_matches('.').to_string()
+}
+
+fn format_seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+/// Build the tag for a single chapter file from the tag of the whole book.
+fn chapter_tag(book: &Tag, chapter: &Chapter, number: usize, total: usize) -> Tag {
+    let mut tag = Tag::with_version(Version::Id3v24);
+
+    for frame in book.frames() {
+        if !matches!(
+            frame.content(),
+            Content::Chapter(_) | Content::TableOfContents(_)
+        ) && !matches!(frame.id(), "TIT2" | "TRCK" | "TLEN")
+        {
+            tag.add_frame(frame.clone());
+        }
+    }
+
+    // The book's title makes a sensible album name for the pieces if there isn't one already.
+    if let (None, Some(title)) = (book.album(), book.title()) {
+        tag.set_album(title);
+    }
+
+    // Chapters can carry their own artist, e.g. when imported from a CUE sheet.
+    if let Some(artist) = chapter.artist() {
+        tag.set_artist(artist);
+    }
+
+    tag.set_title(
+        chapter
+            .title()
+            .map(String::from)
+            .unwrap_or_else(|| format!("Chapter {number}")),
+    );
+    tag.set_track(number as u32);
+    tag.set_total_tracks(total as u32);
+
+    tag
+}
+
+impl Splitter {
+    /// Create a new split of the given input file, writing to the current directory.
+    pub fn new(input: impl Into<PathBuf>) -> Self {
+        Self {
+            input: input.into(),
+            output_dir: PathBuf::from("."),
+            encoding: None,
+            overwrite: false,
+            confirm_overwrite: None,
+            backend: Arc::new(FfmpegBackend),
+        }
+    }
+
+    /// Set the directory to write the chapter files to. It's created if it doesn't exist.
+    pub fn output_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output_dir = path.into();
+        self
+    }
+
+    /// Re-encode each chapter instead of copying the audio stream.
+    ///
+    /// Stream copies can only be cut at MP3 frame boundaries, which is usually close enough.
+    pub fn reencode(mut self, encoding: Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Whether to replace chapter files that already exist. Disabled by default, in which case
+    /// the split fails before cutting anything if any chapter file exists, unless
+    /// [`Splitter::confirm_overwrite`] allows it.
+    pub fn overwrite(mut self, enabled: bool) -> Self {
+        self.overwrite = enabled;
+        self
+    }
+
+    /// Ask before replacing a chapter file that already exists, rather than failing.
+    ///
+    /// `confirm` is called with the path of each such file before any chapter is cut, and the
+    /// split fails unless it returns `true`.
+    pub fn confirm_overwrite(mut self, confirm: fn(&Path) -> anyhow::Result<bool>) -> Self {
+        self.confirm_overwrite = Some(confirm);
+        self
+    }
+
+    /// The backend used to cut the chapters out. Defaults to [`FfmpegBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Split the input at each chapter, writing one tagged MP3 file per chapter.
+    ///
+    /// Files are named after the chapter number and title, and keep the input's global tags
+    /// along with a track number.
+    pub fn split(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let display = self.input.display();
+
+        let book = Tag::read_from_path(&self.input)
+            .with_context(|| format!("failed to read ID3 tag from '{display}'"))?;
+
+        let mut chapters: Vec<_> = book.chapters().collect();
+        chapters.sort_by_key(|chapter| chapter.start_time);
+        anyhow::ensure!(!chapters.is_empty(), "no chapters found in '{display}'");
+
+        fs::create_dir_all(&self.output_dir).with_context(|| {
+            format!(
+                "failed to create output directory '{}'",
+                self.output_dir.display()
+            )
+        })?;
+
+        let width = chapters.len().to_string().len().max(2);
+        let files: Vec<_> = (chapters.iter().enumerate())
+            .map(|(i, chapter)| {
+                let number = i + 1;
+                let tag = chapter_tag(&book, chapter, number, chapters.len());
+                let output = self.output_dir.join(format!(
+                    "{number:0width$} - {}.mp3",
+                    sanitize_file_name(tag.title().unwrap_or_default())
+                ));
+
+                (chapter, tag, output)
+            })
+            .collect();
+
+        // Check every file before cutting any of them, so nothing is left half done.
+        let outputs: Vec<_> = files.iter().map(|(_, _, output)| output.clone()).collect();
+        output::ensure_not_input(&outputs, [&self.input])?;
+
+        for output in &outputs {
+            output::ensure_writable(output, self.overwrite, self.confirm_overwrite)?;
+        }
+
+        let progress_bar = ProgressBar::new(chapters.len() as u64)
+            .with_style(ProgressStyle::default_bar().template("[{pos}/{len}] {spinner} {msg}")?);
+        progress_bar.enable_steady_tick(Duration::from_millis(100));
+
+        for (chapter, tag, output) in &files {
+            let title = tag.title().unwrap_or_default();
+
+            progress_bar.inc(1);
+            progress_bar.set_message(format!("✂️ splitting chapter '{title}'..."));
+
+            // Like merged output, each file only shows up once it's complete.
+            let display = output.display();
+            let temp_file = output::temp_file_for(output, ".mp3")
+                .with_context(|| format!("failed to create temporary file next to '{display}'"))?;
+
+            (self.backend)
+                .cut(
+                    &self.input,
+                    temp_file.path(),
+                    chapter.start_time,
+                    chapter.end_time,
+                    self.encoding,
+                )
+                .with_context(|| format!("failed to cut chapter '{title}'"))?;
+
+            tag.write_to_path(temp_file.path(), Version::Id3v24)
+                .with_context(|| format!("failed to write ID3 metadata to '{display}'"))?;
+
+            interrupt::check()?;
+
+            output::persist(temp_file, output)
+                .with_context(|| format!("failed to move chapter file to '{display}'"))?;
+        }
+
+        progress_bar.set_message("📚 split!");
+        progress_bar.finish();
+
+        Ok(outputs)
+    }
+}
+
+pub(crate) fn ffmpeg_cut(
+    input: &Path,
+    output: &Path,
+    start_ms: u32,
+    end_ms: u32,
+    encoding: Option<Encoding>,
+) -> anyhow::Result<()> {
+    let mut args: Vec<OsString> = vec![
+        "-hide_banner".into(),
+        "-loglevel".into(),
+        "error".into(),
+        "-i".into(),
+        input.into(),
+        "-ss".into(),
+        format_seconds(start_ms).into(),
+        "-to".into(),
+        format_seconds(end_ms).into(),
+        "-map".into(),
+        "0:a".into(),
+        "-map_metadata".into(),
+        "-1".into(),
+        "-id3v2_version".into(),
+        "0".into(),
+    ];
+
+    match encoding {
+        Some(encoding) => {
+            args.extend(["-c:a".into(), "libmp3lame".into()]);
+            args.extend(encoding.mp3_args().map(Into::into));
+        }
+        None => args.extend(["-c".into(), "copy".into()]),
+    }
+
+    args.extend(["-y".into(), output.into()]);
+
+    interrupt::run(duct::cmd("ffmpeg", args))?;
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Helpers shared by the unit tests.
+
+use std::io::Write;
+
+use tempfile::NamedTempFile;
+
+use crate::probe::Probe;
+
+/// A probe of an untagged stereo stream at 44.1 kHz.
+pub(crate) fn probe(codec_name: &str, duration_secs: f64) -> Probe {
+    Probe::new(codec_name, 44_100, 2, duration_secs)
+}
+
+/// A one second probe with the given tags.
+pub(crate) fn tagged_probe(codec_name: &str, tags: &[(&str, &str)]) -> Probe {
+    Probe {
+        tags: (tags.iter())
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        ..probe(codec_name, 1.0)
+    }
+}
+
+/// A temporary file holding `bytes`.
+pub(crate) fn file(bytes: &[u8]) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(bytes).unwrap();
+    file
+}
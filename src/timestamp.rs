@@ -0,0 +1,33 @@
+/// Format milliseconds as `hh:mm:ss.mmm`.
+pub(crate) fn format_timestamp(ms: u32) -> String {
+    let (hours, ms) = (ms / 3_600_000, ms % 3_600_000);
+    let (minutes, ms) = (ms / 60_000, ms % 60_000);
+    let (seconds, ms) = (ms / 1000, ms % 1000);
+
+    format!("{hours:02}:{minutes:02}:{seconds:02}.{ms:03}")
+}
+
+/// Parse `[[hh:]mm:]ss[.mmm]` into milliseconds.
+pub fn parse_timestamp(timestamp: &str) -> Option<u32> {
+    let mut parts = timestamp.rsplit(':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts
+        .next()
+        .map_or(Some(0), |minutes| minutes.parse().ok())?;
+    let hours: u32 = parts.next().map_or(Some(0), |hours| hours.parse().ok())?;
+
+    if parts.next().is_some() || !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+
+    let ms = (hours as f64 * 3600.0 + minutes as f64 * 60.0 + seconds) * 1000.0;
+    (ms <= u32::MAX as f64).then(|| ms.round() as u32)
+}
+
+/// Parse a timestamp with an optional leading `+` or `-` into signed milliseconds.
+pub fn parse_offset(offset: &str) -> Option<i64> {
+    match offset.strip_prefix('-') {
+        Some(offset) => parse_timestamp(offset).map(|ms| -(ms as i64)),
+        None => parse_timestamp(offset.strip_prefix('+').unwrap_or(offset)).map(i64::from),
+    }
+}
@@ -0,0 +1,258 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::Context;
+use id3::frame::Chapter;
+use indicatif::ProgressBar;
+
+use crate::{
+    backend::Backend,
+    chapters::UNUSED_OFFSET,
+    mp3,
+    probe::{self, Packet},
+    timestamp::format_timestamp,
+};
+
+/// Where a concatenated file actually starts and ends in the merged stream, in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The packets of a merged MP3 file, as they ended up after concatenation.
+#[derive(Clone, Debug)]
+pub(crate) struct MergedStream {
+    packets: Vec<Packet>,
+    audio_start: u64,
+}
+
+fn to_ms(secs: f64) -> u32 {
+    (secs.max(0.0) * 1000.0).round() as u32
+}
+
+impl MergedStream {
+    pub fn measure(backend: &dyn Backend, path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            packets: probe::packets(backend, path)?,
+            audio_start: mp3::audio_start(path).with_context(|| {
+                format!("failed to find start of audio in '{}'", path.display())
+            })?,
+        })
+    }
+
+    /// Find each file's span in the merged stream by counting its packets and looking up the
+    /// timestamps of the packets at each join.
+    ///
+    /// Per-file durations don't add up to the merged duration exactly, since encoder delay and
+    /// padding (and rounding) end up in the middle of the merged stream, so this is the only
+    /// reliable way to know where each file ends up.
+    pub fn spans(
+        &self,
+        backend: &dyn Backend,
+        files: &[impl AsRef<Path>],
+    ) -> anyhow::Result<Vec<Span>> {
+        let packets = &self.packets;
+        let mut spans = Vec::with_capacity(files.len());
+        let mut first_packet = 0;
+        let mut previous_end = 0;
+
+        for path in files.iter().map(AsRef::as_ref) {
+            let last_packet = first_packet + probe::count_packets(backend, path)?;
+
+            anyhow::ensure!(
+                last_packet <= packets.len(),
+                "merged file has {} packets, fewer than its inputs",
+                packets.len()
+            );
+
+            let file_packets = &packets[first_packet..last_packet];
+            let span = match (file_packets.first(), file_packets.last()) {
+                (Some(first), Some(last)) => Span {
+                    start: to_ms(first.time),
+                    end: to_ms(last.time + last.duration),
+                },
+                _ => Span {
+                    start: previous_end,
+                    end: previous_end,
+                },
+            };
+
+            spans.push(span);
+            first_packet = last_packet;
+            previous_end = span.end;
+        }
+
+        anyhow::ensure!(
+            first_packet == packets.len(),
+            "merged file has {} packets, but its inputs have {first_packet}",
+            packets.len()
+        );
+
+        Ok(spans)
+    }
+
+    /// The byte offset, from the start of the audio, of the first frame at or after `time`.
+    fn offset_at(&self, time: u32) -> u32 {
+        let i = (self.packets).partition_point(|packet| to_ms(packet.time) < time);
+        let pos = match self.packets.get(i) {
+            Some(packet) => packet.pos,
+            None => (self.packets.last()).map_or(self.audio_start, |last| last.pos + last.size),
+        };
+
+        u32::try_from(pos.saturating_sub(self.audio_start)).unwrap_or(UNUSED_OFFSET)
+    }
+
+    /// Set chapter byte offsets to the frames each chapter starts and ends at, counting from the
+    /// start of the audio.
+    pub fn set_offsets<'a>(&self, chapters: impl IntoIterator<Item = &'a mut Chapter>) {
+        for chapter in chapters {
+            chapter.start_offset = self.offset_at(chapter.start_time);
+            chapter.end_offset = self.offset_at(chapter.end_time);
+        }
+    }
+}
+
+/// Move each file's chapters to where the file actually ended up in the merged stream.
+///
+/// `durations` are the file durations in milliseconds that the chapters were generated from.
+/// Returns the largest correction, in milliseconds.
+pub(crate) fn reconcile(chapters: &mut [Vec<Chapter>], durations: &[u32], spans: &[Span]) -> u32 {
+    let mut drift = 0;
+    let mut file_start: u32 = 0;
+
+    for ((file_chapters, &duration), span) in chapters.iter_mut().zip(durations).zip(spans) {
+        let file_end = file_start + duration;
+        let move_time = |time: u32| {
+            if time >= file_end {
+                span.end
+            } else if time <= file_start {
+                span.start
+            } else {
+                (span.start.saturating_add(time - file_start)).min(span.end)
+            }
+        };
+
+        for chapter in file_chapters {
+            chapter.start_time = move_time(chapter.start_time);
+            chapter.end_time = move_time(chapter.end_time);
+        }
+
+        drift = drift
+            .max(span.start.abs_diff(file_start))
+            .max(span.end.abs_diff(file_end));
+        file_start = file_end;
+    }
+
+    drift
+}
+
+/// Measure the merged stream and correct the chapter times to match it.
+///
+/// Returns the measured stream, for working out chapter offsets.
+pub(crate) fn correct_chapters(
+    backend: &dyn Backend,
+    chapters: &mut [Vec<Chapter>],
+    durations: &[u32],
+    files: &[impl AsRef<Path>],
+    merged: &Path,
+) -> anyhow::Result<MergedStream> {
+    let progress_bar = ProgressBar::new_spinner();
+    progress_bar.set_message("⏱️ measuring chapter timings...");
+    progress_bar.enable_steady_tick(Duration::from_millis(100));
+
+    let stream = MergedStream::measure(backend, merged).context("failed to measure merged file")?;
+    let spans = stream
+        .spans(backend, files)
+        .context("failed to measure merged file")?;
+    let drift = reconcile(chapters, durations, &spans);
+
+    progress_bar.finish_with_message(if drift == 0 {
+        String::from("⏱️ chapter timings match!")
+    } else {
+        format!(
+            "⏱️ corrected chapter drift of up to {}!",
+            format_timestamp(drift)
+        )
+    });
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use id3::TagLike;
+
+    use super::*;
+
+    fn chapter(start_time: u32, end_time: u32) -> Chapter {
+        let mut chapter = Chapter {
+            element_id: format!("chapter_{start_time}"),
+            start_time,
+            end_time,
+            start_offset: UNUSED_OFFSET,
+            end_offset: UNUSED_OFFSET,
+            frames: Vec::new(),
+        };
+        chapter.set_title("title");
+        chapter
+    }
+
+    fn times(chapters: &[Vec<Chapter>]) -> Vec<(u32, u32)> {
+        (chapters.iter().flatten())
+            .map(|chapter| (chapter.start_time, chapter.end_time))
+            .collect()
+    }
+
+    #[test]
+    fn moves_chapters_to_measured_spans() {
+        // The second file has two chapters, and each file turned out 26ms longer than probed.
+        let mut chapters = vec![
+            vec![chapter(0, 1000)],
+            vec![chapter(1000, 1500), chapter(1500, 3000)],
+        ];
+        let spans = [
+            Span {
+                start: 0,
+                end: 1026,
+            },
+            Span {
+                start: 1026,
+                end: 3052,
+            },
+        ];
+
+        let drift = reconcile(&mut chapters, &[1000, 2000], &spans);
+
+        assert_eq!(drift, 52);
+        assert_eq!(times(&chapters), [(0, 1026), (1026, 1526), (1526, 3052)]);
+    }
+
+    #[test]
+    fn keeps_chapters_within_shorter_spans() {
+        let mut chapters = vec![vec![chapter(0, 400), chapter(400, 1000)]];
+        let spans = [Span { start: 0, end: 300 }];
+
+        let drift = reconcile(&mut chapters, &[1000], &spans);
+
+        assert_eq!(drift, 700);
+        assert_eq!(times(&chapters), [(0, 300), (300, 300)]);
+    }
+
+    #[test]
+    fn no_drift_when_spans_match() {
+        let mut chapters = vec![vec![chapter(0, 1000)], vec![chapter(1000, 2500)]];
+        let spans = [
+            Span {
+                start: 0,
+                end: 1000,
+            },
+            Span {
+                start: 1000,
+                end: 2500,
+            },
+        ];
+
+        assert_eq!(reconcile(&mut chapters, &[1000, 1500], &spans), 0);
+        assert_eq!(times(&chapters), [(0, 1000), (1000, 2500)]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use id3::{
+    frame::{Chapter, TableOfContents},
+    TagLike,
+};
+
+/// How to arrange chapters in the table of contents (`CTOC` frames).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TocStructure {
+    /// A single top-level table of contents listing every chapter.
+    #[default]
+    Flat,
+    /// One part per directory, holding the chapters of consecutive inputs from that directory.
+    Directories,
+    /// One part per input file, holding its chapters. Mostly useful with a CUE sheet, where a file
+    /// can have several tracks.
+    Files,
+}
+
+impl FromStr for TocStructure {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "flat" => Ok(Self::Flat),
+            "dirs" | "directories" => Ok(Self::Directories),
+            "files" => Ok(Self::Files),
+            _ => Err(format!(
+                "unknown table of contents structure '{name}', expected flat, dirs or files"
+            )),
+        }
+    }
+}
+
+fn directory_name(path: &Path) -> String {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+
+    fs::canonicalize(&parent)
+        .ok()
+        .as_deref()
+        .and_then(Path::file_name)
+        .unwrap_or(parent.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Build the `CTOC` frames for chapters grouped by the input file they came from.
+///
+/// The first frame is always the top-level table of contents. If the chapters split into more
+/// than one part, it lists the parts, and each part gets its own table of contents listing its
+/// chapters.
+pub(crate) fn build_toc(
+    inputs: &[impl AsRef<Path>],
+    chapters: &[Vec<Chapter>],
+    structure: TocStructure,
+    title: Option<&str>,
+) -> Vec<TableOfContents> {
+    // Group consecutive inputs with the same part title together.
+    let mut parts: Vec<(String, Vec<String>)> = Vec::new();
+
+    for (path, chapters) in inputs.iter().map(AsRef::as_ref).zip(chapters) {
+        let part_title = match structure {
+            TocStructure::Flat => String::new(),
+            TocStructure::Directories => directory_name(path),
+            TocStructure::Files => file_name(path),
+        };
+        let element_ids = chapters.iter().map(|chapter| chapter.element_id.clone());
+
+        match parts.last_mut() {
+            Some((last_title, elements)) if *last_title == part_title => {
+                elements.extend(element_ids)
+            }
+            _ => parts.push((part_title, element_ids.collect())),
+        }
+    }
+
+    let mut top_level = TableOfContents {
+        element_id: String::from("toc"),
+        top_level: true,
+        ordered: true,
+        elements: Vec::new(),
+        frames: Vec::new(),
+    };
+
+    if let Some(title) = title {
+        top_level.set_title(title);
+    }
+
+    if parts.len() <= 1 {
+        top_level.elements = parts
+            .into_iter()
+            .flat_map(|(_, elements)| elements)
+            .collect();
+        return vec![top_level];
+    }
+
+    let mut tables = vec![top_level];
+
+    for (i, (part_title, elements)) in parts.into_iter().enumerate() {
+        let mut part = TableOfContents {
+            element_id: format!("part_{i}"),
+            top_level: false,
+            ordered: true,
+            elements,
+            frames: Vec::new(),
+        };
+
+        part.set_title(part_title);
+        tables[0].elements.push(part.element_id.clone());
+        tables.push(part);
+    }
+
+    tables
+}
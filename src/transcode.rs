@@ -0,0 +1,335 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use tempfile::TempDir;
+
+use crate::{backend::Backend, ffmpeg, format::Format, probe::Probe};
+
+/// Encoder settings used when audio has to be re-encoded.
+///
+/// ffmpeg's AAC encoder has no usable VBR mode, so [`Encoding::Vbr`] only applies to MP3 and AAC
+/// output falls back to [`AAC_DEFAULT_BITRATE`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Constant bitrate, in kbit/s.
+    Bitrate(u32),
+    /// LAME VBR quality, from 0 (best) to 9 (smallest).
+    Vbr(u8),
+}
+
+/// AAC bitrate in kbit/s, used unless a bitrate is given explicitly.
+pub const AAC_DEFAULT_BITRATE: u32 = 64;
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Vbr(2)
+    }
+}
+
+impl Encoding {
+    pub(crate) fn mp3_args(&self) -> [String; 2] {
+        match self {
+            Self::Bitrate(kbps) => [String::from("-b:a"), format!("{kbps}k")],
+            Self::Vbr(quality) => [String::from("-q:a"), quality.to_string()],
+        }
+    }
+
+    /// The average bitrate of MP3 audio encoded this way, in kbit/s. VBR bitrates are the ones
+    /// LAME's documentation gives for each quality.
+    pub(crate) fn mp3_kbps(&self) -> u32 {
+        match self {
+            Self::Bitrate(kbps) => *kbps,
+            Self::Vbr(quality) => [245, 225, 190, 175, 165, 130, 115, 100, 85, 65]
+                .get(usize::from(*quality))
+                .copied()
+                .unwrap_or(65),
+        }
+    }
+
+    pub(crate) fn aac_args(&self) -> [String; 2] {
+        let kbps = match self {
+            Self::Bitrate(kbps) => *kbps,
+            Self::Vbr(_) => AAC_DEFAULT_BITRATE,
+        };
+
+        [String::from("-b:a"), format!("{kbps}k")]
+    }
+}
+
+/// The stream parameters every input must share to be concatenated by the concat demuxer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    pub codec_name: String,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+impl Profile {
+    /// Pick the profile to convert inputs to for the given output format.
+    ///
+    /// MP3 output is stream copied, so every input has to be MP3. Re-encoding MP3 loses quality,
+    /// so the target is whatever sample rate and channel layout most of the MP3 audio already
+    /// has, and only the odd files out are converted. Inputs for M4B output are decoded anyway,
+    /// so any codec works as long as all inputs agree; otherwise everything is converted to
+    /// lossless FLAC first.
+    pub(crate) fn common(probes: &[Probe], format: Format) -> Self {
+        match (format, probes.first()) {
+            (Format::Mp3, _) => {
+                if let Some(profile) = Self::most_common_mp3(probes) {
+                    return profile;
+                }
+            }
+            (Format::M4b, Some(first)) => {
+                let first = Self::of(first);
+
+                if first.channels <= 2 && probes.iter().all(|probe| first.matches(probe)) {
+                    return first;
+                }
+            }
+            (Format::M4b, None) => {}
+        }
+
+        // Neither MP3 nor our intermediates need more than 48 kHz or two channels, so anything
+        // bigger gets resampled/downmixed.
+        Self {
+            codec_name: String::from(match format {
+                Format::Mp3 => "mp3",
+                Format::M4b => "flac",
+            }),
+            sample_rate: probes
+                .iter()
+                .map(|probe| probe.sample_rate)
+                .max()
+                .unwrap_or(44_100)
+                .min(48_000),
+            channels: probes
+                .iter()
+                .map(|probe| probe.channels)
+                .max()
+                .unwrap_or(2)
+                .min(2),
+        }
+    }
+
+    /// The profile of the MP3 inputs with the most audio between them, preferring the higher
+    /// quality one on a tie. `None` if there are no MP3 inputs.
+    fn most_common_mp3(probes: &[Probe]) -> Option<Self> {
+        let mut durations: Vec<((u32, u32), f64)> = Vec::new();
+
+        for probe in probes.iter().filter(|probe| probe.codec_name == "mp3") {
+            let key = (probe.sample_rate, probe.channels);
+
+            match durations.iter_mut().find(|(other, _)| *other == key) {
+                Some((_, duration)) => *duration += probe.duration_secs,
+                None => durations.push((key, probe.duration_secs)),
+            }
+        }
+
+        let ((sample_rate, channels), _) = durations
+            .into_iter()
+            .max_by(|(a_key, a), (b_key, b)| a.total_cmp(b).then(a_key.cmp(b_key)))?;
+
+        Some(Self {
+            codec_name: String::from("mp3"),
+            sample_rate,
+            channels,
+        })
+    }
+
+    /// The profile of a single file, e.g. one that new inputs are being appended to.
+    pub(crate) fn of(probe: &Probe) -> Self {
+        Self {
+            codec_name: probe.codec_name.clone(),
+            sample_rate: probe.sample_rate,
+            channels: probe.channels,
+        }
+    }
+
+    pub(crate) fn matches(&self, probe: &Probe) -> bool {
+        probe.codec_name == self.codec_name
+            && probe.sample_rate == self.sample_rate
+            && probe.channels == self.channels
+    }
+}
+
+/// Transcode every input that doesn't match the common profile into a temporary directory.
+///
+/// Returns the list of files to concatenate, in input order, along with the directory holding
+/// the transcoded files. If all inputs are already compatible, no directory is created.
+pub(crate) fn transcode_inputs(
+    backend: &dyn Backend,
+    inputs: &[impl AsRef<Path>],
+    probes: &[Probe],
+    profile: &Profile,
+    encoding: Encoding,
+) -> anyhow::Result<(Vec<PathBuf>, Option<TempDir>)> {
+    let incompatible = probes
+        .iter()
+        .filter(|probe| !profile.matches(probe))
+        .count();
+
+    if incompatible == 0 {
+        return Ok((
+            inputs.iter().map(|path| path.as_ref().to_owned()).collect(),
+            None,
+        ));
+    }
+
+    let temp_dir = tempfile::Builder::new()
+        .prefix("merge-transcode")
+        .tempdir()
+        .context("failed to create temporary directory for transcoded files")?;
+
+    // Progress is shown across all the files, by how much of their probed duration is done.
+    let total_secs = (probes.iter())
+        .filter(|probe| !profile.matches(probe))
+        .map(|probe| probe.duration_secs)
+        .sum();
+    let progress_bar = ffmpeg::progress_bar(total_secs, "🔄 transcoding input files...")?;
+    let mut done_secs = 0.0;
+
+    let mut files = Vec::with_capacity(inputs.len());
+
+    for (i, (path, probe)) in inputs.iter().map(AsRef::as_ref).zip(probes).enumerate() {
+        if profile.matches(probe) {
+            files.push(path.to_owned());
+            continue;
+        }
+
+        let message = format!("🔄 transcoding '{}'...", path.display());
+        progress_bar.set_message(message.clone());
+
+        let transcoded = temp_dir.path().join(format!("{i}.{}", profile.codec_name));
+        backend
+            .transcode(path, &transcoded, profile, encoding, &mut |secs| {
+                let secs = secs.min(probe.duration_secs);
+                ffmpeg::set_progress(&progress_bar, &message, done_secs + secs);
+            })
+            .with_context(|| format!("failed to transcode input file '{}'", path.display()))?;
+        files.push(transcoded);
+
+        done_secs += probe.duration_secs;
+        ffmpeg::set_progress(&progress_bar, &message, done_secs);
+    }
+
+    progress_bar.set_message("🎶 inputs transcoded!");
+    progress_bar.finish();
+
+    Ok((files, Some(temp_dir)))
+}
+
+pub(crate) fn ffmpeg_transcode(
+    input: &Path,
+    output: &Path,
+    profile: &Profile,
+    encoding: Encoding,
+    progress: &mut dyn FnMut(f64),
+) -> anyhow::Result<()> {
+    let mut args: Vec<OsString> = vec![
+        "-hide_banner".into(),
+        "-loglevel".into(),
+        "error".into(),
+        "-i".into(),
+        input.into(),
+        "-vn".into(),
+        "-map_metadata".into(),
+        "-1".into(),
+        "-ar".into(),
+        profile.sample_rate.to_string().into(),
+        "-ac".into(),
+        profile.channels.to_string().into(),
+    ];
+
+    if profile.codec_name == "mp3" {
+        args.extend(["-c:a".into(), "libmp3lame".into()]);
+        args.extend(encoding.mp3_args().map(Into::into));
+        // Leave out the ID3 tag and Xing header, since these files only exist to be concatenated.
+        args.extend(["-id3v2_version", "0", "-write_xing", "0"].map(Into::into));
+    } else {
+        args.extend(["-c:a".into(), (&profile.codec_name).into()]);
+    }
+
+    args.extend(["-y".into(), output.into()]);
+
+    ffmpeg::run(args, progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(codec_name: &str, sample_rate: u32, channels: u32) -> Profile {
+        Profile {
+            codec_name: String::from(codec_name),
+            sample_rate,
+            channels,
+        }
+    }
+
+    #[test]
+    fn mp3_output_keeps_most_mp3_audio_as_is() {
+        // A stereo 48 kHz intro shouldn't force a mono 44.1 kHz book to be re-encoded.
+        let probes = [
+            Probe::new("flac", 48_000, 2, 30.0),
+            Probe::new("mp3", 48_000, 2, 30.0),
+            Probe::new("mp3", 44_100, 1, 1800.0),
+            Probe::new("mp3", 44_100, 1, 1800.0),
+        ];
+
+        assert_eq!(
+            Profile::common(&probes, Format::Mp3),
+            profile("mp3", 44_100, 1)
+        );
+    }
+
+    #[test]
+    fn mp3_output_goes_by_duration_not_file_count() {
+        let probes = [
+            Probe::new("mp3", 22_050, 1, 10.0),
+            Probe::new("mp3", 22_050, 1, 10.0),
+            Probe::new("mp3", 44_100, 2, 3600.0),
+        ];
+
+        assert_eq!(
+            Profile::common(&probes, Format::Mp3),
+            profile("mp3", 44_100, 2)
+        );
+    }
+
+    #[test]
+    fn mp3_output_without_mp3_inputs_uses_the_best_quality() {
+        let probes = [
+            Probe::new("flac", 96_000, 6, 10.0),
+            Probe::new("flac", 44_100, 1, 10.0),
+        ];
+
+        assert_eq!(
+            Profile::common(&probes, Format::Mp3),
+            profile("mp3", 48_000, 2)
+        );
+    }
+
+    #[test]
+    fn m4b_output_keeps_a_shared_profile() {
+        let probes = [
+            Probe::new("aac", 44_100, 2, 10.0),
+            Probe::new("aac", 44_100, 2, 10.0),
+        ];
+        assert_eq!(
+            Profile::common(&probes, Format::M4b),
+            profile("aac", 44_100, 2)
+        );
+
+        let probes = [
+            Probe::new("aac", 44_100, 2, 10.0),
+            Probe::new("mp3", 48_000, 1, 10.0),
+        ];
+        assert_eq!(
+            Profile::common(&probes, Format::M4b),
+            profile("flac", 48_000, 2)
+        );
+    }
+}
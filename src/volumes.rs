@@ -0,0 +1,262 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use id3::Tag;
+
+use crate::{
+    chapters::UNUSED_OFFSET,
+    format::Format,
+    metadata::{self, Metadata},
+    probe::Probe,
+    transcode::{Encoding, Profile},
+};
+
+/// The longest a single output file can be, since ID3 chapter times are 32-bit milliseconds
+/// (about 49.7 days).
+pub(crate) const MAX_DURATION_MS: u64 = u32::MAX as u64;
+
+/// The biggest a single MP3 output file can be while every chapter offset in it can be
+/// represented, since offsets are 32-bit and the largest value means "unused". The last chapter
+/// ends at the end of the file, so the file has to be smaller than that. M4B chapters don't have
+/// offsets.
+pub(crate) const MAX_SIZE: u64 = UNUSED_OFFSET as u64 - 1;
+
+pub(crate) fn duration_ms(probe: &Probe) -> u64 {
+    (probe.duration_secs.max(0.0) * 1000.0).round() as u64
+}
+
+/// How long and how big the output would be if the given inputs were merged into it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Estimate {
+    pub duration_ms: u64,
+    pub size: u64,
+}
+
+impl Estimate {
+    /// Estimate how much each input adds to the output.
+    ///
+    /// MP3 inputs that are copied as they are add their own size, and transcoded ones are sized by
+    /// the encoding's bitrate. Only MP3 output has a size limit, so sizes for M4B are left at 0.
+    pub fn of(
+        inputs: &[impl AsRef<Path>],
+        probes: &[Probe],
+        format: Format,
+        encoding: Encoding,
+    ) -> anyhow::Result<Vec<Self>> {
+        let profile = Profile::common(probes, format);
+
+        inputs
+            .iter()
+            .map(AsRef::as_ref)
+            .zip(probes)
+            .map(|(path, probe)| {
+                let duration_ms = duration_ms(probe);
+                let size = match format {
+                    Format::M4b => 0,
+                    Format::Mp3 if profile.matches(probe) => fs::metadata(path)
+                        .with_context(|| {
+                            format!("failed to get info for input file '{}'", path.display())
+                        })?
+                        .len(),
+                    // kbit/s times milliseconds is bits.
+                    Format::Mp3 => duration_ms * u64::from(encoding.mp3_kbps()) / 8,
+                };
+
+                Ok(Self { duration_ms, size })
+            })
+            .collect()
+    }
+
+    /// Estimate the tag in front of an output's audio, which holds the global metadata and cover
+    /// along with some padding. M4B output has no size limit, so its tag is left at 0.
+    pub fn tag(metadata: &Metadata, format: Format) -> anyhow::Result<Self> {
+        let size = match format {
+            Format::M4b => 0,
+            Format::Mp3 => {
+                let mut tag = Tag::new();
+                metadata::populate_metadata(metadata, &mut tag, Vec::new(), Vec::new())?;
+                metadata::tag_space(&tag)?
+            }
+        };
+
+        Ok(Self {
+            duration_ms: 0,
+            size,
+        })
+    }
+
+    pub fn total<'a>(estimates: impl IntoIterator<Item = &'a Self>) -> Self {
+        estimates
+            .into_iter()
+            .fold(Self::default(), |total, estimate| Self {
+                duration_ms: total.duration_ms + estimate.duration_ms,
+                size: total.size + estimate.size,
+            })
+    }
+
+    pub fn fits(&self) -> bool {
+        self.duration_ms <= MAX_DURATION_MS && self.size <= MAX_SIZE
+    }
+}
+
+/// Check that a single output file can hold the chapter times of the whole merge.
+///
+/// Chapter offsets past 4 GiB are written as "unused" instead, so size alone isn't a problem.
+pub(crate) fn ensure_duration_fits(total: Estimate) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        total.duration_ms <= MAX_DURATION_MS,
+        "the merged file would be {:.1} days long, but chapter times only go up to about 49.7 \
+         days; split it into volumes instead",
+        total.duration_ms as f64 / 86_400_000.0
+    );
+
+    Ok(())
+}
+
+/// Group consecutive inputs into as few volumes as possible, keeping each volume within the
+/// duration and size limits of a single file.
+///
+/// Every volume starts with a `tag` of its own. A single input that's too big on its own still
+/// gets a volume to itself, as long as it isn't too long.
+pub(crate) fn plan(tag: Estimate, estimates: &[Estimate]) -> anyhow::Result<Vec<Range<usize>>> {
+    let mut volumes = Vec::new();
+    let mut start = 0;
+    let mut current = tag;
+
+    for (i, estimate) in estimates.iter().enumerate() {
+        anyhow::ensure!(
+            estimate.duration_ms <= MAX_DURATION_MS,
+            "input file {} is too long for chapter times to hold, even on its own",
+            i + 1
+        );
+
+        let next = Estimate::total(&[current, *estimate]);
+
+        if i > start && !next.fits() {
+            volumes.push(start..i);
+            start = i;
+            current = Estimate::total(&[tag, *estimate]);
+        } else {
+            current = next;
+        }
+    }
+
+    volumes.push(start..estimates.len());
+
+    Ok(volumes)
+}
+
+/// The path of a numbered volume, e.g. `book (Part 2).mp3` for `book.mp3`.
+pub(crate) fn volume_path(output: &Path, number: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(extension) => format!("{stem} (Part {number}).{}", extension.to_string_lossy()),
+        None => format!("{stem} (Part {number})"),
+    };
+
+    output.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{file, probe};
+
+    #[test]
+    fn estimates_copied_and_transcoded_mp3_sizes() {
+        let inputs = [file(&vec![0; 1000]), file(&vec![0; 50_000_000])];
+        let probes = [probe("mp3", 10.0), probe("flac", 10.0)];
+
+        let estimates = Estimate::of(
+            &inputs.each_ref().map(|f| f.path()),
+            &probes,
+            Format::Mp3,
+            Encoding::Bitrate(64),
+        )
+        .unwrap();
+
+        assert_eq!(
+            estimates,
+            [
+                Estimate {
+                    duration_ms: 10_000,
+                    size: 1000,
+                },
+                Estimate {
+                    duration_ms: 10_000,
+                    size: 80_000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn m4b_has_no_size_limit() {
+        let inputs = [file(&vec![0; 1000])];
+        let estimates = Estimate::of(
+            &inputs.each_ref().map(|f| f.path()),
+            &[probe("aac", 10.0)],
+            Format::M4b,
+            Encoding::default(),
+        )
+        .unwrap();
+
+        assert_eq!(estimates[0].size, 0);
+    }
+
+    #[test]
+    fn plans_volumes_within_limits() {
+        let estimate = |duration_ms, size| Estimate { duration_ms, size };
+        let estimates = [
+            estimate(1000, MAX_SIZE / 2),
+            estimate(1000, MAX_SIZE / 2),
+            estimate(1000, MAX_SIZE / 2),
+            estimate(MAX_DURATION_MS - 500, 0),
+            estimate(1000, MAX_SIZE * 2),
+        ];
+
+        let no_tag = Estimate::default();
+        assert_eq!(plan(no_tag, &estimates).unwrap(), [0..2, 2..3, 3..4, 4..5]);
+        assert_eq!(plan(no_tag, &estimates[..3]).unwrap(), [0..2, 2..3]);
+        assert!(plan(no_tag, &[estimate(MAX_DURATION_MS + 1, 0)]).is_err());
+
+        // Each volume's tag takes up space too.
+        assert_eq!(
+            plan(estimate(0, 1), &estimates[..3]).unwrap(),
+            [0..1, 1..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn estimates_tag_with_cover() {
+        let cover = tempfile::Builder::new().suffix(".jpg").tempfile().unwrap();
+        fs::write(cover.path(), vec![0; 10_000]).unwrap();
+        let metadata = Metadata {
+            title: Some(String::from("Book")),
+            cover: Some(cover.path().to_owned()),
+            ..Default::default()
+        };
+
+        let tag = Estimate::tag(&metadata, Format::Mp3).unwrap();
+        assert_eq!(tag.duration_ms, 0);
+        assert!(tag.size > 10_000 + 4096, "{tag:?}");
+
+        assert_eq!(Estimate::tag(&metadata, Format::M4b).unwrap().size, 0);
+    }
+
+    #[test]
+    fn numbers_volume_paths() {
+        assert_eq!(
+            volume_path(Path::new("dir/book.mp3"), 2),
+            Path::new("dir/book (Part 2).mp3")
+        );
+        assert_eq!(
+            volume_path(Path::new("book"), 1),
+            Path::new("book (Part 1)")
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use id3::{Tag, TagLike};
+use merge::{ChapterEdit, Editor, FakeBackend, Merger, Probe, Splitter};
+use tempfile::TempDir;
+
+/// The header of the silent frames the fake backend transcodes to, 32 kbit/s at 44.1 kHz.
+const SILENT_HEADER: [u8; 4] = [0xff, 0xfb, 0x10, 0x00];
+const SILENT_FRAME_LEN: u32 = 104;
+const FRAME_SECS: f64 = 1152.0 / 44_100.0;
+
+/// Inputs that only the fake backend can make sense of, with the given durations.
+struct Inputs {
+    dir: TempDir,
+    paths: Vec<PathBuf>,
+    backend: FakeBackend,
+}
+
+impl Inputs {
+    fn new(durations: &[f64]) -> Self {
+        let dir = TempDir::new().unwrap();
+        let mut paths = Vec::new();
+        let mut backend = FakeBackend::new();
+
+        for (i, &duration_secs) in durations.iter().enumerate() {
+            let path = dir.path().join(format!("{:02}.flac", i + 1));
+            fs::write(&path, b"fLaC").unwrap();
+            backend = backend.probe_as(&path, Probe::new("flac", 44_100, 2, duration_secs));
+            paths.push(path);
+        }
+
+        Self {
+            dir,
+            paths,
+            backend,
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn merger(&self, output: &Path) -> Merger {
+        Merger::new(output)
+            .inputs(&self.paths)
+            .backend(self.backend.clone())
+    }
+}
+
+/// Where the merged stream is after `frames` silent frames, in milliseconds.
+fn merged_ms(frames: u32) -> u32 {
+    (f64::from(frames) * FRAME_SECS * 1000.0).round() as u32
+}
+
+/// How many silent frames the fake backend writes for `secs`.
+fn silent_frames(secs: f64) -> u32 {
+    (secs / FRAME_SECS).ceil() as u32
+}
+
+fn chapters(tag: &Tag) -> Vec<(String, u32, u32)> {
+    let mut chapters: Vec<_> = tag
+        .chapters()
+        .map(|chapter| {
+            let title = chapter.title().unwrap_or_default().to_string();
+            (title, chapter.start_time, chapter.end_time)
+        })
+        .collect();
+    chapters.sort_by_key(|&(_, start, _)| start);
+    chapters
+}
+
+#[test]
+fn chapters_follow_merged_audio() {
+    let inputs = Inputs::new(&[1.0, 2.0, 0.5]);
+    let output = inputs.path("book.mp3");
+    inputs.merger(&output).title("Book").merge().unwrap();
+
+    // Silence only comes in whole frames, so each file ends up a little longer than probed.
+    let [first, second, third] = [1.0, 2.0, 0.5].map(silent_frames);
+    let ends = [first, first + second, first + second + third].map(merged_ms);
+
+    let tag = Tag::read_from_path(&output).unwrap();
+    assert_eq!(tag.title(), Some("Book"));
+    assert_eq!(
+        chapters(&tag),
+        [
+            (String::from("01"), 0, ends[0]),
+            (String::from("02"), ends[0], ends[1]),
+            (String::from("03"), ends[1], ends[2]),
+        ]
+    );
+}
+
+#[test]
+fn chapter_offsets_point_at_frames() {
+    let inputs = Inputs::new(&[1.0, 2.0]);
+    let output = inputs.path("book.mp3");
+    inputs.merger(&output).merge().unwrap();
+
+    let bytes = fs::read(&output).unwrap();
+    let tag = Tag::read_from_path(&output).unwrap();
+    let mut chapters: Vec<_> = tag.chapters().collect();
+    chapters.sort_by_key(|chapter| chapter.start_time);
+
+    for chapter in &chapters {
+        let start = chapter.start_offset as usize;
+        assert_eq!(bytes[start..start + 4], SILENT_HEADER);
+    }
+
+    assert_eq!(
+        chapters[1].start_offset - chapters[0].start_offset,
+        silent_frames(1.0) * SILENT_FRAME_LEN
+    );
+    assert_eq!(chapters[0].end_offset, chapters[1].start_offset);
+    assert_eq!(chapters[1].end_offset as usize, bytes.len());
+}
+
+#[test]
+fn tag_is_written_in_place() {
+    let inputs = Inputs::new(&[1.0, 2.0]);
+    let output = inputs.path("book.mp3");
+    inputs.merger(&output).title("Book").merge().unwrap();
+
+    // The tag fills the space left for it in front of the audio, with padding to spare.
+    let bytes = fs::read(&output).unwrap();
+    let tag_size = 10 + (bytes[6..10].iter()).fold(0, |size, &b| (size << 7) | usize::from(b));
+    assert!(bytes[tag_size - 1024..tag_size].iter().all(|&b| b == 0));
+    assert_eq!(bytes[tag_size..tag_size + 2], SILENT_HEADER[..2]);
+
+    // So editing it doesn't move the audio, and the offsets still point at frames.
+    Editor::new(&output)
+        .chapter_edit(ChapterEdit::Rename(2, String::from("A longer title")))
+        .edit()
+        .unwrap();
+
+    let edited = fs::read(&output).unwrap();
+    assert_eq!(edited.len(), bytes.len());
+    assert_eq!(edited[tag_size..], bytes[tag_size..]);
+
+    let tag = Tag::read_from_path(&output).unwrap();
+    for chapter in tag.chapters() {
+        let start = chapter.start_offset as usize;
+        assert_eq!(edited[start..start + 4], SILENT_HEADER);
+    }
+}
+
+#[test]
+fn chapters_from_cue_sheet() {
+    let inputs = Inputs::new(&[60.0, 45.0]);
+    let sheet = inputs.path("book.cue");
+
+    // As written by EAC, with the second track's pregap at the end of the first file.
+    fs::write(
+        &sheet,
+        r#"
+        FILE "01.flac" WAVE
+          TRACK 01 AUDIO
+            TITLE "One"
+            INDEX 01 00:00:00
+          TRACK 02 AUDIO
+            TITLE "Two"
+            INDEX 00 00:58:00
+        FILE "02.flac" WAVE
+            INDEX 01 00:00:00
+          TRACK 03 AUDIO
+            TITLE "Three"
+            INDEX 01 00:30:00
+        "#,
+    )
+    .unwrap();
+
+    let output = inputs.path("book.mp3");
+    inputs
+        .merger(&output)
+        .chapters_from_cue(&sheet)
+        .merge()
+        .unwrap();
+
+    let first_end = merged_ms(silent_frames(60.0));
+    let second_end = merged_ms(silent_frames(60.0) + silent_frames(45.0));
+    let tag = Tag::read_from_path(&output).unwrap();
+
+    assert_eq!(
+        chapters(&tag),
+        [
+            (String::from("One"), 0, first_end),
+            (String::from("Two"), first_end, first_end + 30_000),
+            (String::from("Three"), first_end + 30_000, second_end),
+        ]
+    );
+}
+
+#[test]
+fn m4b_gets_chapters_from_probed_durations() {
+    let inputs = Inputs::new(&[1.0, 2.0]);
+    let output = inputs.path("book.m4b");
+    inputs.merger(&output).title("Book").merge().unwrap();
+
+    // The fake backend writes the metadata it's given instead of encoding anything.
+    let ffmetadata = fs::read_to_string(&output).unwrap();
+    let expected = [
+        "title=Book",
+        "[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=1000\ntitle=01",
+        "[CHAPTER]\nTIMEBASE=1/1000\nSTART=1000\nEND=3000\ntitle=02",
+    ];
+
+    for lines in expected {
+        assert!(ffmetadata.contains(lines), "{ffmetadata}");
+    }
+}
+
+#[test]
+fn splits_volumes_that_are_too_long() {
+    // 60 days altogether, which chapter times can't hold in one file.
+    let day = 86_400.0;
+    let inputs = Inputs::new(&[20.0 * day, 20.0 * day, 20.0 * day]);
+    let output = inputs.path("book.mp3");
+
+    let err = inputs.merger(&output).merge().unwrap_err();
+    assert!(err.to_string().contains("60.0 days long"), "{err}");
+
+    // Both parts are checked before either is written, which saves actually merging them.
+    let second_part = inputs.path("book (Part 2).mp3");
+    fs::write(&second_part, b"").unwrap();
+
+    let err = inputs
+        .merger(&output)
+        .split_volumes(true)
+        .merge()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("output file '{}' already exists", second_part.display())
+    );
+    assert!(!inputs.path("book (Part 1).mp3").exists());
+}
+
+#[test]
+fn refuses_to_replace_inputs() {
+    let inputs = Inputs::new(&[1.0]);
+
+    let err = inputs
+        .merger(&inputs.paths[0])
+        .format(merge::Format::Mp3)
+        .overwrite(true)
+        .merge()
+        .unwrap_err();
+    assert!(err.to_string().contains("is also an input file"), "{err}");
+}
+
+#[test]
+fn splits_chapters_back_out() {
+    let inputs = Inputs::new(&[1.0, 2.0]);
+    let book = inputs.path("book.mp3");
+    inputs.merger(&book).title("Book").merge().unwrap();
+
+    let outputs = Splitter::new(&book)
+        .output_dir(inputs.path("chapters"))
+        .backend(inputs.backend.clone())
+        .split()
+        .unwrap();
+
+    assert_eq!(
+        outputs,
+        [
+            inputs.path("chapters/01 - 01.mp3"),
+            inputs.path("chapters/02 - 02.mp3")
+        ]
+    );
+
+    for (i, (output, secs)) in outputs.iter().zip([1.0, 2.0]).enumerate() {
+        let tag = Tag::read_from_path(output).unwrap();
+        assert_eq!(tag.title(), Some(format!("{:02}", i + 1).as_str()));
+        assert_eq!(tag.album(), Some("Book"));
+        assert_eq!(tag.track(), Some(i as u32 + 1));
+
+        // Each chapter gets the silent frames of the file it came from.
+        let bytes = fs::read(output).unwrap();
+        let audio_start = (bytes.windows(4))
+            .position(|window| window == SILENT_HEADER)
+            .unwrap();
+        let audio_len = (bytes.len() - audio_start) as u32;
+        assert_eq!(audio_len, silent_frames(secs) * SILENT_FRAME_LEN);
+    }
+
+    // Existing chapter files are only replaced when asked to.
+    let splitter = Splitter::new(&book)
+        .output_dir(inputs.path("chapters"))
+        .backend(inputs.backend.clone());
+    let err = splitter.clone().split().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("output file '{}' already exists", outputs[0].display())
+    );
+    splitter.overwrite(true).split().unwrap();
+}